@@ -1,378 +1,5416 @@
+#[cfg(not(target_os = "linux"))]
+compile_error!("resolvconffs relies on Linux network namespaces and /proc/<pid>/ns/net, so it only builds for Linux targets");
+
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
 use std::path::PathBuf;
-use std::time::{Duration, SystemTime};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use gumdrop::Options;
+use nix::fcntl::OFlag;
+
+/// Special FUSE filesystem that maps its sole file to other files based on network namespace of process that queries the file.
+/// To be used for /etc/resolv.conf in setups where network namespaces are used without accompanying mount namespaces (without /etc/netns)
+#[derive(Options)]
+struct Opts {
+    help: bool,
+
+    /// Directory where to look for resolv.conf-like files for each netns.
+    #[options(free)]
+    backing_directory: Option<PathBuf>,
+
+    /// Filename extension. resolvconffs maps its file to <backing_directory>/<netns_identifier><postfix> (default: conf). Comma-separated list means trying extensions in order and using the first existing file; missing files get the first extension.
+    #[options(short = 'p')]
+    extension: Option<PathBuf>,
+
+    /// In case of target file does not exist, copy this file to target instead of returning ENOENT. If copying fails, the default file itself is served read-only.
+    #[options(short = 'd')]
+    default_file: Option<PathBuf>,
+
+    /// Like --default-file, but populate missing files with this text. `-` means reading the text from stdin at startup.
+    #[options(no_short)]
+    default_content: Option<String>,
+
+    /// `<uid>:<path>`, use this default file instead of --default-file when requestor has this uid. May be repeated.
+    #[options(no_short, meta = "UID:PATH")]
+    default_file_for: Vec<String>,
+
+    /// Where to get identifier of requesting process from: `netns` (default), `pidfile:<dir>` for reading it from <dir>/<pid> files or `comm` for process name
+    #[options(no_short)]
+    key_source: Option<String>,
+
+    /// Directory where to look up network namespace IDs based on PIDs. (default: /proc)
+    #[options(short = 'P')]
+    procfs: Option<PathBuf>,
+
+    #[options(free)]
+    mountpoint_file: Option<PathBuf>,
+
+    /// Additional mount options, comma-separated or in multiple `-o`s.
+    #[options(short = 'o', long = "fuse-opt")]
+    other_fuse_opts: Vec<String>,
+
+    /// Resolve backing directory inside requesting process's mount namespace (via <procfs>/<pid>/root) instead of resolvconffs's own one.
+    #[options(no_short)]
+    requestor_root: bool,
+
+    /// Chown files populated from default file to uid and gid of the process that triggered the copy, so users can edit them. Requires root.
+    #[options(no_short)]
+    own_created_files: bool,
+
+    /// Stop populating missing files from default file after this number of copies
+    #[options(no_short)]
+    max_default_copies: Option<u64>,
+
+    /// Stop populating missing files from default file after this number of bytes copied in total
+    #[options(no_short)]
+    default_copy_total_bytes: Option<u64>,
+
+    /// Use names of `ip netns` namespaces instead of numeric identifiers where available, e.g. <backing_directory>/foo.conf
+    #[options(no_short)]
+    resolve_netns_names: bool,
+
+    /// Look up netns of the requesting thread's thread group leader (`Tgid:` from <procfs>/<pid>/status) instead of the thread itself
+    #[options(no_short)]
+    use_tgid: bool,
+
+    /// Directory with named network namespace bind mounts, for --resolve-netns-names
+    #[options(no_short, default = "/run/netns")]
+    netns_dir: PathBuf,
+
+    /// File with `<netns_identifier> <name>` lines. Matching identifiers are replaced with the name before building target file path.
+    #[options(no_short)]
+    id_map_file: Option<PathBuf>,
+
+    /// Report startup sanity check problems only at debug log level
+    #[options(short = 'q')]
+    quiet: bool,
+
+    /// Exit instead of just warning if startup sanity checks find a problem
+    #[options(no_short)]
+    strict: bool,
+
+    /// Serve `user.resolvconffs.etag` extended attribute that changes when the backing file is changed or replaced
+    #[options(no_short)]
+    virtual_etag: bool,
+
+    /// `<uid>:<gid>`, report this file owner instead of the backing file's one. Does not change the backing file.
+    #[options(no_short, meta = "UID:GID")]
+    report_owner: Option<String>,
+
+    /// Allow kernel page cache for the file instead of direct I/O. Faster for frequent reads, but files that stay open would not notice backing file changes made bypassing the mount.
+    #[options(no_short)]
+    page_cache: bool,
+
+    /// Let kernel cache file attributes (size, mtime, ...) for this number of milliseconds. 0 means always asking resolvconffs, so backing file changes are visible immediately.
+    #[options(no_short, default = "0")]
+    attr_timeout_ms: u64,
+
+    /// Prepend content of this file (read at startup) to the served file. Writes through the mount are rejected with EROFS in this mode.
+    #[options(no_short)]
+    read_prefix: Option<PathBuf>,
+
+    /// Remember content of backing files read in full and serve it read-only while the backing file is inaccessible (e.g. during NFS outage). Served content may be arbitrarily old.
+    #[options(no_short)]
+    stale_ok: bool,
+
+    /// Memory limit for content remembered by --stale-ok
+    #[options(no_short, default = "1048576")]
+    stale_max_bytes: usize,
+
+    /// Remember mapping result for each requesting uid/gid/pid for this number of milliseconds. 0 disables the cache.
+    #[options(no_short, default = "0")]
+    cache_ttl_ms: u64,
+
+    /// With --cache-ttl-ms, always map opens for writing and truncations anew instead of using cached results. Reads may still be served from a mapping that is up to the TTL old.
+    #[options(no_short)]
+    fresh_on_write_open: bool,
+
+    /// Count bytes read and written through the mount per requestor uid. Counters are served as `user.resolvconffs.io_bytes` extended attribute and logged on unmount.
+    #[options(no_short)]
+    io_accounting: bool,
+
+    /// Check permissions in resolvconffs too, on top of the kernel's `default_permissions` checks, letting root bypass them like on a normal filesystem. Supplementary groups are read from `<procfs>/<pid>/status`.
+    #[options(no_short)]
+    no_default_permissions_for_root: bool,
+
+    /// Writable directory layered over backing_directory, which becomes read-only lower layer. Files are served from here if present, otherwise from backing_directory; opening for writing copies the file up here first, failing with EROFS if copying fails.
+    #[options(no_short)]
+    upper_dir: Option<PathBuf>,
+
+    /// With --upper-dir, modify files in backing_directory in place when resolvconffs may write them, copying them up only when they are not writable (read-only filesystem or missing permissions)
+    #[options(no_short)]
+    cow_on_write_open: bool,
+
+    /// If mountpoint_file is a symlink (e.g. /etc/resolv.conf pointing to systemd-resolved's file), mount over its final target instead of refusing to start
+    #[options(no_short)]
+    resolve_mountpoint_symlink: bool,
+
+    /// Give processes in the init network namespace (the one of pid 1) read-only access, rejecting modifications with EROFS
+    #[options(no_short)]
+    init_netns_readonly: bool,
+
+    /// Fail opens with EMFILE when this many files are already open through the mount
+    #[options(no_short)]
+    max_open_handles: Option<usize>,
+
+    /// Spread files into 256 subdirectories of backing_directory, like <backing_directory>/eb/4026532413.conf. See README for the sharding function.
+    #[options(no_short)]
+    shard: bool,
+
+    /// Honour RESOLVCONFFS_FORCE_ID environment variable, mapping all requests to this identifier without looking at netns. For tests on machines without netns support, not for production.
+    #[options(no_short)]
+    allow_force_id: bool,
+
+    /// Open backing files with O_NOATIME to avoid access time updates on each read, where permitted (files owned by resolvconffs's user or when running as root)
+    #[options(no_short)]
+    noatime: bool,
+
+    /// File with `<netns_identifier> ro|rw` and `uid:<uid> ro|rw` lines, restricting modifications of mapped files. Reloaded on SIGHUP.
+    #[options(no_short)]
+    policy_file: Option<PathBuf>,
+
+    /// `ro` or `rw` (the default), access for identifiers and uids not listed in --policy-file. Needs --policy-file.
+    #[options(no_short)]
+    default_policy: Option<String>,
+
+    /// Keep a copy of each backing file written through the mount in this directory, under the same file name. The copy is made by re-reading the backing file on fsync and close.
+    #[options(no_short)]
+    mirror_dir: Option<PathBuf>,
+
+    /// Chroot into this directory after mounting. Procfs and backing directory (and other directories in use) must be bind-mounted there at the same paths.
+    #[options(no_short)]
+    chroot: Option<PathBuf>,
+
+    /// Join the network or mount namespace of this nsfs file (e.g. `/proc/<pid>/ns/mnt`) at startup, before looking at any other paths. May be repeated.
+    #[options(no_short, meta = "PATH")]
+    setns: Vec<PathBuf>,
+
+    /// Refuse to serve requests whose identifier (after name lookups and id map) is longer than this number of bytes
+    #[options(no_short, default = "255")]
+    max_id_len: usize,
+
+    /// At startup, check whether backing directory is on a case-insensitive filesystem, where identifiers differing only in case would share a file
+    #[options(no_short)]
+    case_sensitive_check: bool,
+
+    /// Comma-separated list of FUSE operations to serve, others fail with EPERM. Operations: getattr, open, fsync, read, write, setattr, getxattr, listxattr, access. Default is all. Opening for writing also needs write, and with O_TRUNC setattr too.
+    #[options(no_short, meta = "OPS")]
+    allowed_ops: Option<String>,
+
+    /// Mount the filesystem on this file (created if missing) and bind-mount it onto mountpoint_file. Useful when mountpoint_file is on a read-only filesystem where mounting FUSE directly is refused.
+    #[options(no_short)]
+    mount_via: Option<PathBuf>,
+
+    /// At startup, stat backing files of network namespaces of running processes, so that first requests find them in kernel caches. Runs no hooks and creates no files.
+    #[options(no_short)]
+    prewarm: bool,
+
+    /// Fail backing file operations (open, stat, read, write, fsync, and existence checks and default file copies while mapping) with ETIMEDOUT if they take longer than this, e.g. on a hung NFS mount. Stuck operations are left running in up to 16 background threads, further operations fail with EIO while all of them are stuck.
+    #[options(no_short)]
+    io_timeout_ms: Option<u64>,
+
+    /// Report distinct stable inode number for each backing file (hash of its path) instead of the same number for everybody
+    #[options(no_short)]
+    hash_ino: bool,
+
+    /// Serve this file to processes in resolvconffs's own network namespace, instead of a file in backing directory
+    #[options(no_short)]
+    host_file: Option<PathBuf>,
+
+    /// Serve minimal built-in resolv.conf, read-only, when the mapped backing file cannot be accessed, so DNS keeps working despite misconfiguration. Requests that cannot be mapped (e.g. denied by --policy-file) still fail.
+    #[options(no_short)]
+    emergency_fallback: bool,
+
+    /// Nameserver in --emergency-fallback content
+    #[options(no_short, default = "8.8.8.8")]
+    emergency_nameserver: String,
+
+    /// Allow backing files to be symlinks. By default they are opened with O_NOFOLLOW, so that a user able to write to backing directory cannot redirect other users to arbitrary files. Backing files that are not regular files (FIFOs, devices, directories) are refused either way.
+    #[options(no_short)]
+    follow_backing_symlinks: bool,
+
+    /// Log each FUSE operation with requestor's uid, gid and pid and operation's arguments, at trace log level (e.g. RUST_LOG=resolvconffs=trace)
+    #[options(no_short)]
+    log_requests: bool,
+
+    /// Serve `user.resolvconffs.backing_path` extended attribute with the backing file path mapped for the requestor. Reveals host paths to everyone who can access the mount.
+    #[options(no_short)]
+    expose_debug_xattrs: bool,
+
+    /// Buffer sequential writes in memory, up to 64 KiB per open file, passing them to the backing file on fsync, close, or when the file gets read or stat'ed. Errors of buffered writes are reported by the following write, fsync or close.
+    #[options(no_short)]
+    coalesce_writes: bool,
+
+    /// Print effective configuration, after applying environment variables and defaults, as JSON object to stdout and exit
+    #[options(no_short)]
+    print_config: bool,
+
+    /// Append `-<starttime>` to identifiers, with the process start time in clock ticks since boot from `<procfs>/<pid>/stat`, to tell apart reused netns ids
+    #[options(no_short)]
+    key_include_starttime: bool,
+
+    /// Run resolvconffs threads only on these CPUs after mounting, e.g. `0-1,4`
+    #[options(no_short, meta = "CPUS")]
+    cpu_affinity: Option<String>,
+
+    /// Set nice value of resolvconffs threads after mounting. Values below the current one require CAP_SYS_NICE.
+    #[options(no_short)]
+    nice: Option<i32>,
+
+    /// Run resolvconffs threads with SCHED_FIFO real-time policy at this priority (1-99) after mounting. Requires CAP_SYS_NICE or RLIMIT_RTPRIO.
+    #[options(no_short)]
+    rt_priority: Option<i32>,
+
+    /// Serve only identifiers and uids listed in --policy-file, failing with ENOENT for others without populating files for them
+    #[options(no_short)]
+    deny_by_default: bool,
+
+    /// Text to put before the identifier in backing file names, e.g. `ns-` for `<backing_directory>/ns-<netns_identifier>.conf`
+    #[options(no_short)]
+    id_prefix: String,
+
+    /// Text to put after the identifier in backing file names, before the extension
+    #[options(no_short)]
+    id_suffix: String,
+
+    /// Mount over the file open as this inherited descriptor (via `/proc/self/fd/<fd>`) instead of mountpoint_file argument, for launchers that set up the mountpoint themselves. Needs root, as fusermount refuses such mountpoints.
+    #[options(no_short, meta = "FD")]
+    mountpoint_fd: Option<i32>,
+
+    /// Append a line with time, uid, gid, pid, identifier and served path to this file when an identifier is first resolved for a uid, and when its served path changes. Reopened on SIGHUP for log rotation.
+    #[options(no_short)]
+    audit_log: Option<PathBuf>,
+
+    /// `native` serves backing files as they are, `crlf` converts LF line endings to CRLF on reads and back on writes, buffering whole files in memory
+    #[options(no_short, default = "native")]
+    line_endings: String,
+
+    /// Refuse to serve backing files larger than this number of bytes, failing open and stat with EFBIG
+    #[options(no_short)]
+    max_file_size: Option<u64>,
+
+    /// Program to run, with the identifier as its argument, the first time an identifier is seen. It is not waited for, so it races with populating the file from the default file.
+    #[options(no_short, meta = "PROGRAM")]
+    on_new_netns: Option<PathBuf>,
+}
+
+const LOG_DEDUP_WINDOW: Duration = Duration::from_secs(10);
+
+/// Rate limiter for warnings that may be triggered by every request.
+/// Within `LOG_DEDUP_WINDOW` only the first message with a given format string gets printed, the rest are just counted.
+#[derive(Default)]
+struct LogDedup {
+    seen: HashMap<&'static str, (Instant, u64)>,
+}
+
+impl LogDedup {
+    fn allow(&mut self, template: &'static str) -> bool {
+        let now = Instant::now();
+        match self.seen.get_mut(template) {
+            Some((since, suppressed)) if now.duration_since(*since) < LOG_DEDUP_WINDOW => {
+                *suppressed += 1;
+                false
+            }
+            Some((since, suppressed)) => {
+                if *suppressed > 0 {
+                    log::warn!(
+                        "{} more message(s) like {:?} suppressed in last {:?}",
+                        suppressed,
+                        template,
+                        now.duration_since(*since)
+                    );
+                }
+                *since = now;
+                *suppressed = 0;
+                true
+            }
+            None => {
+                self.seen.insert(template, (now, 0));
+                true
+            }
+        }
+    }
+}
+
+macro_rules! warn_ratelimited {
+    ($dedup:expr, $fmt:literal $(, $arg:expr)* $(,)?) => {
+        if log::log_enabled!(log::Level::Warn) && $dedup.allow($fmt) {
+            log::warn!($fmt $(, $arg)*);
+        }
+    };
+}
+
+impl Opts {
+    /// Fill in options missing from command line from `RESOLVCONFFS_*` environment variables.
+    /// When only one positional argument is given and `RESOLVCONFFS_BACKING_DIR` is set, the argument is the mountpoint.
+    fn merge_env(&mut self) -> std::io::Result<()> {
+        let env = |name| std::env::var_os(name).map(PathBuf::from);
+        if self.mountpoint_fd.is_some() && self.mountpoint_file.is_some() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "--mountpoint-fd and mountpoint_file argument are mutually exclusive",
+            ));
+        }
+        if self.mountpoint_file.is_none() && self.mountpoint_fd.is_none() && env("RESOLVCONFFS_BACKING_DIR").is_some() {
+            self.mountpoint_file = self.backing_directory.take();
+        }
+        self.backing_directory = self.backing_directory.take().or_else(|| env("RESOLVCONFFS_BACKING_DIR"));
+        self.extension = self.extension.take().or_else(|| env("RESOLVCONFFS_EXTENSION"));
+        self.default_file = self.default_file.take().or_else(|| env("RESOLVCONFFS_DEFAULT_FILE"));
+        self.procfs = self.procfs.take().or_else(|| env("RESOLVCONFFS_PROCFS"));
+        self.mountpoint_file = match self.mountpoint_fd {
+            // `--procfs` may point at a fixture or another pid namespace's procfs, where `self` is not this process
+            Some(fd) => Some(PathBuf::from(format!("/proc/self/fd/{}", fd))),
+            None => self.mountpoint_file.take().or_else(|| env("RESOLVCONFFS_MOUNTPOINT")),
+        };
+        if self.other_fuse_opts.is_empty() {
+            if let Ok(x) = std::env::var("RESOLVCONFFS_FUSE_OPTS") {
+                self.other_fuse_opts.push(x);
+            }
+        }
+
+        for (value, name) in [
+            (&self.backing_directory, "backing_directory"),
+            (&self.mountpoint_file, "mountpoint_file"),
+        ] {
+            if value.is_none() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("missing required free argument {}", name),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn backing_directory(&self) -> &Path {
+        self.backing_directory.as_deref().unwrap_or(Path::new(""))
+    }
+
+    fn mountpoint_file(&self) -> &Path {
+        self.mountpoint_file.as_deref().unwrap_or(Path::new(""))
+    }
+
+    fn extension(&self) -> &Path {
+        self.extension.as_deref().unwrap_or(Path::new("conf"))
+    }
+
+    fn procfs(&self) -> &Path {
+        self.procfs.as_deref().unwrap_or(Path::new("/proc"))
+    }
+
+    /// `--print-config` output: JSON object with long option names as keys
+    fn config_json(&self) -> String {
+        let mut out = String::from("{");
+        macro_rules! field {
+            ($name:ident, $val:expr) => {
+                if out.len() > 1 {
+                    out.push(',');
+                }
+                json_string(&stringify!($name).replace('_', "-"), &mut out);
+                out.push(':');
+                $val.to_json(&mut out);
+            };
+        }
+        field!(backing_directory, self.backing_directory());
+        field!(extension, self.extension());
+        field!(default_file, self.default_file);
+        field!(default_content, self.default_content);
+        field!(default_file_for, self.default_file_for);
+        field!(key_source, self.key_source);
+        field!(procfs, self.procfs());
+        field!(mountpoint_file, self.mountpoint_file());
+        field!(other_fuse_opts, self.other_fuse_opts);
+        field!(requestor_root, self.requestor_root);
+        field!(own_created_files, self.own_created_files);
+        field!(max_default_copies, self.max_default_copies);
+        field!(default_copy_total_bytes, self.default_copy_total_bytes);
+        field!(resolve_netns_names, self.resolve_netns_names);
+        field!(use_tgid, self.use_tgid);
+        field!(netns_dir, self.netns_dir);
+        field!(id_map_file, self.id_map_file);
+        field!(quiet, self.quiet);
+        field!(strict, self.strict);
+        field!(virtual_etag, self.virtual_etag);
+        field!(report_owner, self.report_owner);
+        field!(page_cache, self.page_cache);
+        field!(attr_timeout_ms, self.attr_timeout_ms);
+        field!(read_prefix, self.read_prefix);
+        field!(stale_ok, self.stale_ok);
+        field!(stale_max_bytes, self.stale_max_bytes);
+        field!(cache_ttl_ms, self.cache_ttl_ms);
+        field!(fresh_on_write_open, self.fresh_on_write_open);
+        field!(io_accounting, self.io_accounting);
+        field!(no_default_permissions_for_root, self.no_default_permissions_for_root);
+        field!(upper_dir, self.upper_dir);
+        field!(cow_on_write_open, self.cow_on_write_open);
+        field!(resolve_mountpoint_symlink, self.resolve_mountpoint_symlink);
+        field!(init_netns_readonly, self.init_netns_readonly);
+        field!(max_open_handles, self.max_open_handles);
+        field!(shard, self.shard);
+        field!(allow_force_id, self.allow_force_id);
+        field!(noatime, self.noatime);
+        field!(policy_file, self.policy_file);
+        field!(default_policy, self.default_policy.clone().unwrap_or_else(|| "rw".to_owned()));
+        field!(mirror_dir, self.mirror_dir);
+        field!(chroot, self.chroot);
+        field!(setns, self.setns);
+        field!(max_id_len, self.max_id_len);
+        field!(case_sensitive_check, self.case_sensitive_check);
+        field!(allowed_ops, self.allowed_ops);
+        field!(mount_via, self.mount_via);
+        field!(prewarm, self.prewarm);
+        field!(io_timeout_ms, self.io_timeout_ms);
+        field!(hash_ino, self.hash_ino);
+        field!(host_file, self.host_file);
+        field!(emergency_fallback, self.emergency_fallback);
+        field!(emergency_nameserver, self.emergency_nameserver);
+        field!(follow_backing_symlinks, self.follow_backing_symlinks);
+        field!(log_requests, self.log_requests);
+        field!(expose_debug_xattrs, self.expose_debug_xattrs);
+        field!(coalesce_writes, self.coalesce_writes);
+        field!(key_include_starttime, self.key_include_starttime);
+        field!(cpu_affinity, self.cpu_affinity);
+        field!(nice, self.nice);
+        field!(rt_priority, self.rt_priority);
+        field!(deny_by_default, self.deny_by_default);
+        field!(id_prefix, self.id_prefix);
+        field!(id_suffix, self.id_suffix);
+        field!(mountpoint_fd, self.mountpoint_fd);
+        field!(audit_log, self.audit_log);
+        field!(line_endings, self.line_endings);
+        field!(max_file_size, self.max_file_size);
+        field!(on_new_netns, self.on_new_netns);
+        out.push('}');
+        out
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, PartialOrd, Ord, Eq, Debug, Hash)]
+pub struct UidGidPid {
+    pub uid: u32,
+    pub gid: u32,
+    pub pid: u32,
+    /// The file is going to be modified, e.g. opened for writing or truncated
+    pub write: bool,
+}
+
+/// Result of mapping a request to a backing file
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Mapping {
+    pub path: PathBuf,
+    /// Reject modifications with EROFS
+    pub read_only: bool,
+    pub source: Source,
+    /// Serve this read-only instead of a backing file, for mappers synthesizing the file.
+    /// `path` is then only used for logging and inode numbers.
+    pub content: Option<Arc<[u8]>>,
+}
+
+/// Where content of a mapped file came from, reported as `SOURCE_XATTR`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Source {
+    /// Own file of the requestor
+    Own,
+    /// Copy of a default file (unmodified since copying), or the default file itself
+    Default,
+    /// Populated from built-in content, unmodified since then
+    Generated,
+}
+
+impl Source {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Source::Own => "own",
+            Source::Default => "default",
+            Source::Generated => "generated",
+        }
+    }
+}
+
+impl Mapping {
+    /// Mapping to in-memory `content` without any backing file. Modifications fail with EROFS.
+    pub fn from_content(content: impl Into<Arc<[u8]>>) -> Mapping {
+        Mapping {
+            path: PathBuf::new(),
+            read_only: true,
+            source: Source::Generated,
+            content: Some(content.into()),
+        }
+    }
+}
+
+impl From<PathBuf> for Mapping {
+    fn from(path: PathBuf) -> Mapping {
+        Mapping {
+            path,
+            read_only: false,
+            source: Source::Own,
+            content: None,
+        }
+    }
+}
+
+trait_set::trait_set! {
+    pub trait Mapper = FnMut(UidGidPid) -> Option<Mapping> + Send;
+}
+
+/// Upper bound of entries in `CachingMapper` before expired ones get purged
+const CACHE_MAX_ENTRIES: usize = 4096;
+
+/// Wrapper around other `Mapper` that remembers its results for some time and counts cache hits and misses.
+pub struct CachingMapper<M: Mapper> {
+    inner: M,
+    ttl: Duration,
+    cache: HashMap<UidGidPid, (Instant, Mapping)>,
+    /// Bypass the cache for requests with `write` set
+    fresh_writes: bool,
+    hits: u64,
+    misses: u64,
+}
+
+impl<M: Mapper> CachingMapper<M> {
+    pub fn new(inner: M, ttl: Duration) -> Self {
+        Self {
+            inner,
+            ttl,
+            cache: HashMap::new(),
+            fresh_writes: false,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Map modifying requests with the inner mapper every time, so they see e.g. id map changes immediately
+    pub fn set_fresh_writes(&mut self, fresh_writes: bool) {
+        self.fresh_writes = fresh_writes;
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+
+    pub fn map(&mut self, rq: UidGidPid) -> Option<Mapping> {
+        if self.ttl.is_zero() || (rq.write && self.fresh_writes) {
+            return (self.inner)(rq);
+        }
+        let now = Instant::now();
+        if let Some((t, x)) = self.cache.get(&rq) {
+            if now.duration_since(*t) < self.ttl {
+                self.hits += 1;
+                return Some(x.clone());
+            }
+        }
+        self.misses += 1;
+        if (self.hits + self.misses) % 1024 == 0 {
+            log::debug!("Mapping cache: {} hits, {} misses", self.hits, self.misses);
+        }
+
+        // Failed mappings are not remembered, so that a fixed up setup is picked up immediately
+        let x = (self.inner)(rq)?;
+        if self.cache.len() >= CACHE_MAX_ENTRIES {
+            let ttl = self.ttl;
+            self.cache.retain(|_, (t, _)| now.duration_since(*t) < ttl);
+            if self.cache.len() >= CACHE_MAX_ENTRIES {
+                self.cache.clear();
+            }
+        }
+        self.cache.insert(rq, (now, x.clone()));
+        Some(x)
+    }
+}
+
+/// Bookkeeping for a backing file descriptor handed to the kernel as `fh`
+struct OpenHandle {
+    /// Number of FUSE opens sharing this descriptor
+    refs: usize,
+    /// Backing file this handle was opened for
+    path: PathBuf,
+    /// In-memory content served instead of using a descriptor
+    content: Option<Arc<[u8]>>,
+    /// Uid of the process that opened the handle, for `FsOptions::io_accounting`
+    uid: u32,
+    /// Written since the last `FsOptions::mirror_dir` update
+    dirty: bool,
+    /// Offset and data of writes not passed to the backing file yet, for `FsOptions::coalesce_writes`
+    pending: Option<(i64, Vec<u8>)>,
+    /// Error of a deferred write, reported by the next `fsync`, `write` or `release` of the handle
+    write_error: Option<nix::errno::Errno>,
+    /// Whole file content with CRLF line endings, read and written instead of the descriptor, for `FsOptions::crlf`
+    converted: Option<Vec<u8>>,
+    /// `converted` was modified since it got written to the backing file
+    converted_dirty: bool,
+}
+
+/// Upper bound of bytes returned by one `read`
+const READ_MAX_BYTES: u32 = 4096 * 16;
+
+/// Buffered writes of a handle are passed to the backing file when reaching this size, for `FsOptions::coalesce_writes`
+const COALESCE_WRITES_MAX_BYTES: usize = 64 * 1024;
+
+/// `fh` values of in-memory handles start here, so they do not clash with file descriptors
+const MEMORY_FH_BASE: u64 = 1 << 32;
+
+/// Last known good content of backing files, for `FsOptions::stale_ok`
+#[derive(Default)]
+struct StaleCache {
+    entries: HashMap<PathBuf, (Arc<[u8]>, nix::sys::stat::FileStat)>,
+    bytes: usize,
+}
+
+impl StaleCache {
+    fn insert(&mut self, path: &Path, content: &[u8], st: nix::sys::stat::FileStat, max_bytes: usize) {
+        if let Some((old, _)) = self.entries.remove(path) {
+            self.bytes -= old.len();
+        }
+        if content.len() > max_bytes {
+            return;
+        }
+        while self.bytes + content.len() > max_bytes {
+            let victim = match self.entries.keys().next() {
+                Some(x) => x.clone(),
+                None => break,
+            };
+            if let Some((old, _)) = self.entries.remove(&victim) {
+                self.bytes -= old.len();
+            }
+        }
+        self.bytes += content.len();
+        self.entries.insert(path.to_owned(), (content.into(), st));
+    }
+}
+
+/// Maximum number of uids tracked for `FsOptions::io_accounting`
+const IO_ACCOUNTING_MAX_ENTRIES: usize = 1024;
+/// Counters of uids inactive for this long may be dropped to make room for new ones
+const IO_ACCOUNTING_IDLE: Duration = Duration::from_secs(3600);
+
+/// Bytes transferred through the mount on behalf of a uid
+#[derive(Clone, Copy)]
+struct IoCounters {
+    read: u64,
+    written: u64,
+    last_active: Instant,
+}
+
+/// Inode number of the served file.
+/// resolvconffs is mounted over a regular file, so the file is the root of the mount and gets FUSE's reserved root inode number.
+/// There are no other inodes.
+pub const FILE_INO: u64 = fuser::FUSE_ROOT_ID;
+
+/// Name of virtual extended attribute with a cheap change detection tag of the backing file
+pub const ETAG_XATTR: &str = "user.resolvconffs.etag";
+
+/// Name of virtual extended attribute telling `Mapping::source` of the file: `own`, `default` or `generated`
+pub const SOURCE_XATTR: &str = "user.resolvconffs.source";
+
+/// Name of virtual extended attribute with `FsOptions::io_accounting` counters
+pub const IO_BYTES_XATTR: &str = "user.resolvconffs.io_bytes";
+
+/// Name of virtual extended attribute with the backing file path, for `FsOptions::debug_xattrs`
+pub const BACKING_PATH_XATTR: &str = "user.resolvconffs.backing_path";
+
+/// Names of FUSE operations that can be disabled with `FsOptions::allowed_ops`.
+/// `release` is always allowed, so that opened files get closed.
+pub const OPERATIONS: [&str; 9] = [
+    "getattr", "open", "fsync", "read", "write", "setattr", "getxattr", "listxattr", "access",
+];
+
+/// Set of `OPERATIONS`
+#[derive(Clone, Copy, Debug)]
+pub struct OpSet(u32);
+
+impl Default for OpSet {
+    fn default() -> OpSet {
+        OpSet((1 << OPERATIONS.len()) - 1)
+    }
+}
+
+impl OpSet {
+    /// Parse comma-separated operation names
+    pub fn parse(list: &str) -> std::io::Result<OpSet> {
+        let mut set = 0;
+        for name in list.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+            match OPERATIONS.iter().position(|x| *x == name) {
+                Some(i) => set |= 1 << i,
+                None => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("Unknown operation {:?}, expected one of {}", name, OPERATIONS.join(", ")),
+                    ))
+                }
+            }
+        }
+        Ok(OpSet(set))
+    }
+
+    pub fn allows(&self, op: &str) -> bool {
+        OPERATIONS
+            .iter()
+            .position(|x| *x == op)
+            .map_or(false, |i| self.0 & (1 << i) != 0)
+    }
+
+    /// Whether `open` with `flags` is allowed. Writable descriptors need `write` too,
+    /// and `O_TRUNC` needs `write` and `setattr`, as otherwise opening would bypass their restriction.
+    pub fn allows_open(&self, flags: i32) -> bool {
+        self.allows("open")
+            && (flags & libc::O_ACCMODE == libc::O_RDONLY || self.allows("write"))
+            && (flags & libc::O_TRUNC == 0 || (self.allows("write") && self.allows("setattr")))
+    }
+}
+
+/// Tunables of `FileMapperFs`
+#[derive(Default, Clone, Debug)]
+pub struct FsOptions {
+    /// Serve `ETAG_XATTR` extended attribute, made of inode number, mtime and size of the backing file
+    pub virtual_etag: bool,
+    /// Remember content of backing files read in full and serve it read-only when the backing file becomes inaccessible
+    pub stale_ok: bool,
+    /// Memory limit for content remembered due to `stale_ok`
+    pub stale_max_bytes: usize,
+    /// Uid and gid to report as file owner instead of backing file's ones
+    pub report_owner: Option<(u32, u32)>,
+    /// Let the kernel cache file content instead of using direct I/O.
+    /// Cached pages are dropped on each open, so content changed behind our back is seen by the next open, but not by already open files.
+    pub page_cache: bool,
+    /// How long the kernel may cache attributes. Zero makes it ask on every access.
+    pub attr_ttl: Duration,
+    /// Content prepended to the backing file on reads. Makes the file read-only.
+    pub read_prefix: Option<Arc<[u8]>>,
+    /// Count bytes read and written per uid of the opening process, serving them as `IO_BYTES_XATTR`.
+    /// Root sees counters of all uids, others only their own.
+    pub io_accounting: bool,
+    /// Check access against reported attributes in the handlers too, not only in the kernel with `default_permissions`.
+    /// Uid 0 bypasses the checks. Supplementary groups of the requestor are read from `procfs`.
+    pub permission_checks: bool,
+    /// Procfs to look up requestors in, `/proc` if unset
+    pub procfs: Option<PathBuf>,
+    /// Limit of simultaneously open handles, to protect resolvconffs from running out of file descriptors
+    pub max_open_handles: Option<usize>,
+    /// Open backing files with `O_NOATIME`, falling back to normal open if not permitted
+    pub noatime: bool,
+    /// Directory to copy backing files to after they are modified through the mount.
+    /// Copies are made from the backing file on fsync and close, failures are just logged.
+    pub mirror_dir: Option<PathBuf>,
+    /// Operations to serve, others fail with EPERM
+    pub allowed_ops: OpSet,
+    /// Deadline for backing file syscalls, see `with_deadline`
+    pub io_timeout: Option<Duration>,
+    /// Report inode numbers from `InoTable` instead of `FILE_INO`
+    pub hash_ino: bool,
+    /// Content to serve read-only as a last resort, when neither backing file nor `stale_ok` content is available
+    pub emergency_content: Option<Arc<[u8]>>,
+    /// Open backing files even if they are symlinks, instead of failing with ELOOP
+    pub follow_symlinks: bool,
+    /// Log every operation at trace level
+    pub log_requests: bool,
+    /// Serve `BACKING_PATH_XATTR` extended attribute
+    pub debug_xattrs: bool,
+    /// Buffer sequential writes of a handle in memory, up to `COALESCE_WRITES_MAX_BYTES`.
+    /// Buffers are flushed on `flush`, `fsync` and `release`, and before any operation that may look at file content or size.
+    pub coalesce_writes: bool,
+    /// Serve LF line endings as CRLF, converting written data back. Handles buffer the whole converted file,
+    /// which is written back to the backing file on `flush`, `fsync`, `release` and before other operations look at the file.
+    pub crlf: bool,
+    /// Fail with EFBIG for backing files larger than this, and never read past it
+    pub max_file_size: Option<u64>,
+}
+
+/// Template of `FsOptions::emergency_content` served with `--emergency-fallback`, `{}` being the nameserver
+const EMERGENCY_RESOLV_CONF: &str = "# resolvconffs emergency fallback: backing file is unavailable\nnameserver {}\n";
+
+/// 64-bit FNV-1a hash of `bytes`
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in bytes {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// Upper bound of paths remembered in `InoTable`
+const INO_TABLE_MAX_ENTRIES: usize = 65536;
+
+/// Synthesized inode numbers for backing files, for `FsOptions::hash_ino`.
+/// Numbers are FNV-1a hashes of backing file paths, colliding ones get the next free number.
+/// The table is emptied when it reaches `INO_TABLE_MAX_ENTRIES`; paths then get the same numbers again, except previously colliding ones.
+#[derive(Default)]
+struct InoTable {
+    by_ino: HashMap<u64, PathBuf>,
+    by_path: HashMap<PathBuf, u64>,
+}
+
+impl InoTable {
+    fn ino_for(&mut self, path: &Path) -> u64 {
+        self.ino_with_hash(path, fnv1a(path.as_os_str().as_bytes()))
+    }
+
+    /// Inode number of `path`, starting probing for a free one at `hash`
+    fn ino_with_hash(&mut self, path: &Path, hash: u64) -> u64 {
+        if let Some(ino) = self.by_path.get(path) {
+            return *ino;
+        }
+        if self.by_path.len() >= INO_TABLE_MAX_ENTRIES {
+            self.by_ino.clear();
+            self.by_path.clear();
+        }
+        let mut ino = hash;
+        // 0 is invalid and FILE_INO is reserved for the root
+        while ino <= FILE_INO || self.by_ino.contains_key(&ino) {
+            ino = ino.wrapping_add(1);
+        }
+        self.by_ino.insert(ino, path.to_owned());
+        self.by_path.insert(path.to_owned(), ino);
+        ino
+    }
+}
+
+pub struct FileMapperFs<F: Mapper> {
+    mapper: F,
+    opts: FsOptions,
+    handles: HashMap<u64, OpenHandle>,
+    next_memory_fh: u64,
+    stale: StaleCache,
+    io_counters: HashMap<u32, IoCounters>,
+    inos: InoTable,
+    log_dedup: LogDedup,
+}
+
+impl<F: Mapper> FileMapperFs<F> {
+    /// Backing file of the request, ENODATA if it is mapped to in-memory content
+    fn get_backing_file(&mut self, rq: &fuser::Request<'_>, write: bool) -> nix::Result<PathBuf> {
+        match self.get_mapping(rq, write)? {
+            Mapping { content: Some(_), .. } => Err(nix::errno::Errno::ENODATA),
+            x => Ok(x.path),
+        }
+    }
+
+    fn get_mapping(&mut self, rq: &fuser::Request<'_>, write: bool) -> nix::Result<Mapping> {
+        match (self.mapper)(UidGidPid {
+            uid: rq.uid(),
+            gid: rq.gid(),
+            pid: rq.pid(),
+            write,
+        }) {
+            Some(x) => Ok(x),
+            None => Err(nix::errno::Errno::ENOENT),
+        }
+    }
+
+    pub fn new(mapper: F) -> Self {
+        Self::with_options(mapper, FsOptions::default())
+    }
+
+    pub fn with_options(mapper: F, opts: FsOptions) -> Self {
+        Self {
+            mapper,
+            opts,
+            handles: HashMap::new(),
+            next_memory_fh: MEMORY_FH_BASE,
+            stale: StaleCache::default(),
+            io_counters: HashMap::new(),
+            inos: InoTable::default(),
+            log_dedup: LogDedup::default(),
+        }
+    }
+
+    fn register_handle(&mut self, fh: u64, path: &Path, uid: u32) {
+        self.handles
+            .entry(fh)
+            .or_insert_with(|| OpenHandle {
+                refs: 0,
+                path: path.to_owned(),
+                content: None,
+                uid,
+                dirty: false,
+                pending: None,
+                write_error: None,
+                converted: None,
+                converted_dirty: false,
+            })
+            .refs += 1;
+    }
+
+    /// Fail with EMFILE if `FsOptions::max_open_handles` handles, in-memory ones included, are open already
+    fn check_handle_limit(&mut self, path: &Path) -> nix::Result<()> {
+        match self.opts.max_open_handles {
+            Some(max) if self.handles.len() >= max => {
+                warn_ratelimited!(self.log_dedup, "Refusing to open {:?}: --max-open-handles reached", path);
+                Err(nix::errno::Errno::EMFILE)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn register_memory_handle(&mut self, content: Arc<[u8]>, path: &Path, uid: u32) -> nix::Result<u64> {
+        self.check_handle_limit(path)?;
+        let fh = self.next_memory_fh;
+        self.next_memory_fh += 1;
+        self.handles.insert(
+            fh,
+            OpenHandle {
+                refs: 1,
+                path: path.to_owned(),
+                content: Some(content),
+                uid,
+                dirty: false,
+                pending: None,
+                write_error: None,
+                converted: None,
+                converted_dirty: false,
+            },
+        );
+        Ok(fh)
+    }
+
+    /// Inode number to report for backing file `path`
+    fn reported_ino(&mut self, path: &Path) -> u64 {
+        if self.opts.hash_ino {
+            self.inos.ino_for(path)
+        } else {
+            FILE_INO
+        }
+    }
+
+    /// EROFS if resolvconffs itself refuses modifying `mapping`, opened as `fh`: read-only mappings, in-memory content and `FsOptions::read_prefix`.
+    /// Refusals by the backing file or filesystem are left to `write_denied_errno` and the backing syscalls.
+    fn check_writable(&self, mapping: &Mapping, fh: Option<u64>) -> nix::Result<()> {
+        if self.opts.read_prefix.is_some() || mapping.read_only || mapping.content.is_some() || fh.and_then(|fh| self.memory_content(fh)).is_some() {
+            Err(nix::errno::Errno::EROFS)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// `permits` for the requestor of `rq`
+    fn permits(&self, rq: &fuser::Request<'_>, st: &nix::sys::stat::FileStat, mask: i32) -> bool {
+        let procfs = self.opts.procfs.as_deref().unwrap_or_else(|| Path::new("/proc"));
+        let groups = || match PidDir::open(procfs, rq.pid()) {
+            Ok(dir) => read_groups(&dir).unwrap_or_default(),
+            Err(_) => Vec::new(),
+        };
+        permits(st, &self.opts, rq.uid(), rq.gid(), groups, mask)
+    }
+
+    /// Truncate backing file `bf` to `size` for setattr, via handle `fh` if there is one
+    fn truncate_backing(&mut self, bf: &Path, size: u64, fh: Option<u64>) -> nix::Result<()> {
+        let nofollow = if self.opts.follow_symlinks { OFlag::empty() } else { OFlag::O_NOFOLLOW };
+        if let Some(fh) = fh {
+            self.check_handle(fh)?;
+            let converted = self.handles.get_mut(&fh).and_then(|h| h.converted.as_mut().map(|x| (x, &mut h.converted_dirty)));
+            if let Some((content, dirty)) = converted {
+                content.resize(usize::try_from(size).map_err(|_| nix::errno::Errno::EFBIG)?, 0);
+                *dirty = true;
+            } else {
+                nix::unistd::ftruncate(fh as i32, to_off_t(size).map_err(|_| nix::errno::Errno::EFBIG)?)?;
+            }
+            self.mark_dirty(fh);
+        } else if self.opts.crlf && size != 0 {
+            // Size is in terms of converted content
+            let size = usize::try_from(size).map_err(|_| nix::errno::Errno::EFBIG)?;
+            let mut content = to_crlf(&read_file(bf)?);
+            content.resize(size, 0);
+            let fd = open_regular(bf, OFlag::O_WRONLY | nofollow, nix::sys::stat::Mode::empty())?;
+            let content = from_crlf(&content);
+            let result = nix::unistd::ftruncate(fd, 0).and_then(|()| pwrite_retrying(fd, &content, 0));
+            let _ = nix::unistd::close(fd);
+            if result? != content.len() {
+                return Err(nix::errno::Errno::EIO);
+            }
+            self.mirror(bf);
+        } else {
+            let size = to_off_t(size).map_err(|_| nix::errno::Errno::EFBIG)?;
+            let fd = open_regular(bf, OFlag::O_WRONLY | OFlag::O_CLOEXEC | nofollow, nix::sys::stat::Mode::empty())?;
+            let result = nix::unistd::ftruncate(fd, size);
+            let _ = nix::unistd::close(fd);
+            result?;
+            self.mirror(bf);
+        }
+        Ok(())
+    }
+
+    /// Attributes to reply to setattr with. Those of the descriptor reflect a truncation even if the path got replaced meanwhile.
+    fn attr_after_setattr(&self, bf: &Path, fh: Option<u64>) -> nix::Result<nix::sys::stat::FileStat> {
+        match fh.filter(|fh| self.check_handle(*fh).is_ok() && self.memory_content(*fh).is_none()) {
+            Some(fh) => {
+                let mut st = nix::sys::stat::fstat(fh as i32)?;
+                if let Some(content) = self.handles.get(&fh).and_then(|h| h.converted.as_ref()) {
+                    st.st_size = to_off_t(content.len())?;
+                }
+                Ok(st)
+            }
+            None => self.served_attr(bf, stat_backing(bf, self.opts.follow_symlinks)?),
+        }
+    }
+
+    /// Attributes `st` of backing file `bf` as served: EFBIG if it is too big, size after conversion of line endings
+    fn served_attr(&self, bf: &Path, mut st: nix::sys::stat::FileStat) -> nix::Result<nix::sys::stat::FileStat> {
+        if too_big(&st, &self.opts) {
+            return Err(nix::errno::Errno::EFBIG);
+        }
+        if self.opts.crlf {
+            st.st_size = crlf_size(bf)?;
+        }
+        Ok(st)
+    }
+
+    /// `FOPEN_*` flags for replying to open
+    fn fopen_flags(&self) -> u32 {
+        if self.opts.page_cache {
+            0
+        } else {
+            fuser::consts::FOPEN_DIRECT_IO
+        }
+    }
+
+    fn memory_content(&self, fh: u64) -> Option<Arc<[u8]>> {
+        self.handles.get(&fh).and_then(|h| h.content.clone())
+    }
+
+    /// Fail with EBADF if `fh` was not handed out by `open`, so that arbitrary descriptors of resolvconffs itself are never touched
+    fn check_handle(&self, fh: u64) -> nix::Result<()> {
+        if self.handles.contains_key(&fh) {
+            Ok(())
+        } else {
+            Err(nix::errno::Errno::EBADF)
+        }
+    }
+
+    fn mark_dirty(&mut self, fh: u64) {
+        if let Some(h) = self.handles.get_mut(&fh) {
+            h.dirty = true;
+        }
+    }
+
+    /// Update `FsOptions::mirror_dir` copy of the file behind `fh` if it was written
+    fn mirror_handle(&mut self, fh: u64) {
+        let path = match self.handles.get_mut(&fh) {
+            Some(h) if h.dirty => {
+                h.dirty = false;
+                h.path.clone()
+            }
+            _ => return,
+        };
+        self.mirror(&path);
+    }
+
+    fn mirror(&mut self, path: &Path) {
+        let mirror_dir = match self.opts.mirror_dir {
+            Some(ref x) => x,
+            None => return,
+        };
+        let dst = mirror_dir.join(path.file_name().unwrap_or_default());
+        if let Err(e) = copy_atomically(path, &dst) {
+            warn_ratelimited!(self.log_dedup, "Cannot mirror {:?} to {:?}: {}", path, dst, e);
+        }
+    }
+
+    /// Run `f` on backing descriptor `fh` within `FsOptions::io_timeout`.
+    /// With a timeout `f` gets a duplicate of `fh`, as a timed out call may complete after `fh` got closed and its number reused.
+    fn on_backing_fd<T: Send + 'static>(&self, fh: u64, f: impl FnOnce(i32) -> nix::Result<T> + Send + 'static) -> nix::Result<T> {
+        if self.opts.io_timeout.is_none() {
+            return f(fh as i32);
+        }
+        let fd = nix::unistd::dup(fh as i32)?;
+        let call = move || {
+            let ret = f(fd);
+            let _ = nix::unistd::close(fd);
+            ret
+        };
+        with_deadline(self.opts.io_timeout, call, drop)
+    }
+
+    /// Write `data` at `offset` to backing descriptor `fh`, returning number of bytes written
+    fn pwrite_backing(&self, fh: u64, data: &[u8], offset: i64) -> nix::Result<usize> {
+        let offset = to_off_t(offset).map_err(|_| nix::errno::Errno::EFBIG)?;
+        if self.opts.io_timeout.is_none() {
+            return pwrite_retrying(fh as i32, data, offset);
+        }
+        let data = data.to_vec();
+        self.on_backing_fd(fh, move |fd| pwrite_retrying(fd, &data, offset))
+    }
+
+    /// Append `data` to writes buffered in handle `fh`, flushing them first if `data` does not continue them
+    fn buffer_write(&mut self, fh: u64, data: &[u8], offset: i64) -> nix::Result<usize> {
+        let h = self.handles.get_mut(&fh).ok_or(nix::errno::Errno::EBADF)?;
+        if let Some(e) = h.write_error.take() {
+            return Err(e);
+        }
+        let len = match h.pending {
+            Some((start, ref mut buf)) if start + buf.len() as i64 == offset => {
+                buf.extend_from_slice(data);
+                buf.len()
+            }
+            _ => {
+                self.flush_writes(fh)?;
+                let h = self.handles.get_mut(&fh).ok_or(nix::errno::Errno::EBADF)?;
+                h.pending = Some((offset, data.to_vec()));
+                data.len()
+            }
+        };
+        if len >= COALESCE_WRITES_MAX_BYTES {
+            self.flush_writes(fh)?;
+        }
+        Ok(data.len())
+    }
+
+    /// Pass writes buffered in handle `fh` to the backing file, returning error of this or an earlier deferred write
+    fn flush_writes(&mut self, fh: u64) -> nix::Result<()> {
+        let h = match self.handles.get_mut(&fh) {
+            Some(h) => h,
+            None => return Ok(()),
+        };
+        if let Some(e) = h.write_error.take() {
+            return Err(e);
+        }
+        if h.converted_dirty {
+            h.converted_dirty = false;
+            let data = from_crlf(h.converted.as_deref().unwrap_or_default());
+            nix::unistd::ftruncate(fh as i32, 0)?;
+            h.pending = Some((0, data));
+        }
+        let (mut offset, data) = match h.pending.take() {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        let mut data = &data[..];
+        while !data.is_empty() {
+            match self.pwrite_backing(fh, data, offset)? {
+                0 => return Err(nix::errno::Errno::ENOSPC),
+                n => {
+                    data = &data[n..];
+                    offset += n as i64;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Pass buffered writes of all handles to backing files, so that the current operation sees them.
+    /// Errors are kept for the handle they belong to.
+    fn flush_all_writes(&mut self) {
+        if !self.opts.coalesce_writes && !self.opts.crlf {
+            return;
+        }
+        let fhs: Vec<u64> = self
+            .handles
+            .iter()
+            .filter(|(_, h)| h.pending.is_some() || h.converted_dirty)
+            .map(|(fh, _)| *fh)
+            .collect();
+        for fh in fhs {
+            if let Err(e) = self.flush_writes(fh) {
+                if let Some(h) = self.handles.get_mut(&fh) {
+                    h.write_error = Some(e);
+                }
+            }
+        }
+    }
+
+    /// Drop one reference to `fh`, closing the descriptor when nobody uses it anymore
+    fn unregister_handle(&mut self, fh: u64) -> nix::Result<()> {
+        let h = self.handles.get_mut(&fh).ok_or(nix::errno::Errno::EBADF)?;
+        h.refs -= 1;
+        if h.refs > 0 {
+            return Ok(());
+        }
+        self.mirror_handle(fh);
+        if let Some(h) = self.handles.remove(&fh) {
+            if h.content.is_some() {
+                return Ok(());
+            }
+        }
+        nix::unistd::close(fh as i32)
+    }
+
+    /// Read up to `size` bytes from backing file or in-memory content of handle `fh`
+    fn read_backing(&mut self, fh: u64, offset: i64, size: u32) -> nix::Result<Vec<u8>> {
+        self.check_handle(fh)?;
+        if let Some(content) = self.memory_content(fh) {
+            let start = buf_index(offset).min(content.len());
+            let end = start.saturating_add(size as usize).min(content.len());
+            return Ok(content[start..end].to_vec());
+        }
+        if let Some(content) = self.handles.get(&fh).and_then(|h| h.converted.as_ref()) {
+            let start = buf_index(offset).min(content.len());
+            let end = start.saturating_add(size as usize).min(content.len());
+            return Ok(content[start..end].to_vec());
+        }
+        let file_offset = to_off_t(offset)?;
+        let buf = self.on_backing_fd(fh, move |fd| {
+            let mut buf = vec![0u8; size as usize];
+            let ret = nix::sys::uio::pread(fd, &mut buf[..], file_offset)?;
+            buf.truncate(ret);
+            Ok(buf)
+        })?;
+        let ret = buf.len();
+        if self.opts.stale_ok && offset == 0 && (ret as u32) < size {
+            // Whole file got read, remember it for the case it becomes inaccessible later
+            if let (Some(h), Ok(st)) = (self.handles.get(&fh), nix::sys::stat::fstat(fh as i32)) {
+                self.stale.insert(&h.path, &buf, st, self.opts.stale_max_bytes);
+            }
+        }
+        Ok(buf)
+    }
+
+    /// Add transferred bytes to counters of the uid that opened `fh`
+    fn account_io(&mut self, fh: u64, read: usize, written: usize) {
+        if !self.opts.io_accounting {
+            return;
+        }
+        let uid = match self.handles.get(&fh) {
+            Some(h) => h.uid,
+            None => return,
+        };
+        let now = Instant::now();
+        if !self.io_counters.contains_key(&uid) && self.io_counters.len() >= IO_ACCOUNTING_MAX_ENTRIES {
+            self.io_counters
+                .retain(|_, c| now.duration_since(c.last_active) < IO_ACCOUNTING_IDLE);
+            if self.io_counters.len() >= IO_ACCOUNTING_MAX_ENTRIES {
+                let victim = self
+                    .io_counters
+                    .iter()
+                    .min_by_key(|(_, c)| c.last_active)
+                    .map(|(uid, _)| *uid);
+                if let Some(victim) = victim {
+                    self.io_counters.remove(&victim);
+                }
+            }
+        }
+        let c = self.io_counters.entry(uid).or_insert(IoCounters {
+            read: 0,
+            written: 0,
+            last_active: now,
+        });
+        c.read += read as u64;
+        c.written += written as u64;
+        c.last_active = now;
+    }
+
+    /// `IO_BYTES_XATTR` value: `<uid> <read> <written>` lines, sorted by uid
+    fn io_counters_report(&self, requestor_uid: u32) -> String {
+        let mut uids: Vec<u32> = self
+            .io_counters
+            .keys()
+            .copied()
+            .filter(|x| requestor_uid == 0 || *x == requestor_uid)
+            .collect();
+        uids.sort_unstable();
+        let mut report = String::new();
+        for uid in uids {
+            let c = &self.io_counters[&uid];
+            report.push_str(&format!("{} {} {}\n", uid, c.read, c.written));
+        }
+        report
+    }
+
+    /// Content and attributes to serve instead of inaccessible backing file, if `stale_ok` allows it
+    fn stale_fallback(&mut self, path: &Path) -> Option<(Arc<[u8]>, nix::sys::stat::FileStat)> {
+        if !self.opts.stale_ok {
+            return None;
+        }
+        let (content, mut st) = self.stale.entries.get(path)?.clone();
+        warn_ratelimited!(self.log_dedup, "Serving last known content of inaccessible {:?}", path);
+        st.st_size = content.len() as _;
+        Some((content, st))
+    }
+
+    /// Stale content of `path` if any, otherwise `emergency_content`
+    fn fallback(&mut self, path: &Path) -> Option<(Arc<[u8]>, nix::sys::stat::FileStat)> {
+        if let Some(x) = self.stale_fallback(path) {
+            return Some(x);
+        }
+        let content = self.opts.emergency_content.clone()?;
+        if self.log_dedup.allow("emergency") {
+            log::error!("Serving emergency fallback content instead of {:?}", path);
+        }
+        let st = memory_stat(&content);
+        Some((content, st))
+    }
+}
+
+/// Upper bound of `IoPool` threads. Calls stuck on a hung filesystem keep their thread busy; once all are, further calls fail with EIO right away.
+const IO_POOL_MAX_THREADS: usize = 16;
+
+/// Threads running `with_deadline` calls, started on demand and reused
+struct IoPool {
+    state: std::sync::Mutex<IoPoolState>,
+    wakeup: std::sync::Condvar,
+}
+
+#[derive(Default)]
+struct IoPoolState {
+    jobs: std::collections::VecDeque<Box<dyn FnOnce() + Send>>,
+    threads: usize,
+    idle: usize,
+}
+
+impl IoPool {
+    fn get() -> &'static IoPool {
+        static INIT: std::sync::Once = std::sync::Once::new();
+        static POOL: std::sync::atomic::AtomicPtr<IoPool> = std::sync::atomic::AtomicPtr::new(std::ptr::null_mut());
+        INIT.call_once(|| {
+            let pool = IoPool {
+                state: std::sync::Mutex::new(IoPoolState::default()),
+                wakeup: std::sync::Condvar::new(),
+            };
+            POOL.store(Box::into_raw(Box::new(pool)), std::sync::atomic::Ordering::Release);
+        });
+        // Set once above and never freed
+        unsafe { &*POOL.load(std::sync::atomic::Ordering::Acquire) }
+    }
+
+    /// Run `job` on an idle thread or a new one, failing with EIO if all `IO_POOL_MAX_THREADS` are busy
+    fn submit(&'static self, job: Box<dyn FnOnce() + Send>) -> nix::Result<()> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if state.idle > state.jobs.len() {
+            state.jobs.push_back(job);
+            self.wakeup.notify_one();
+            return Ok(());
+        }
+        if state.threads >= IO_POOL_MAX_THREADS {
+            return Err(nix::errno::Errno::EIO);
+        }
+        let spawned = std::thread::Builder::new()
+            .name("resolvconffs-io".to_owned())
+            .spawn(move || self.work(job));
+        if spawned.is_err() {
+            return Err(nix::errno::Errno::EAGAIN);
+        }
+        state.threads += 1;
+        Ok(())
+    }
+
+    fn work(&self, first: Box<dyn FnOnce() + Send>) {
+        first();
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        loop {
+            match state.jobs.pop_front() {
+                Some(job) => {
+                    drop(state);
+                    job();
+                    state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+                }
+                None => {
+                    state.idle += 1;
+                    state = self.wakeup.wait(state).unwrap_or_else(|e| e.into_inner());
+                    state.idle -= 1;
+                }
+            }
+        }
+    }
+}
+
+/// Run `f` on an `IoPool` thread, failing with ETIMEDOUT if it does not finish within `timeout`.
+/// A syscall in flight cannot be cancelled, so a timed out `f` keeps running in background and `late` gets its eventual result for cleanup.
+/// Without `timeout`, `f` is run inline.
+fn with_deadline<T: Send + 'static>(
+    timeout: Option<Duration>,
+    f: impl FnOnce() -> nix::Result<T> + Send + 'static,
+    late: impl FnOnce(T) + Send + 'static,
+) -> nix::Result<T> {
+    let timeout = match timeout {
+        Some(x) => x,
+        None => return f(),
+    };
+    let (tx, rx) = std::sync::mpsc::channel();
+    IoPool::get().submit(Box::new(move || {
+        if let Err(std::sync::mpsc::SendError(Ok(x))) = tx.send(f()) {
+            late(x);
+        }
+    }))?;
+    rx.recv_timeout(timeout).unwrap_or(Err(nix::errno::Errno::ETIMEDOUT))
+}
+
+/// `with_deadline` for a `std` call, reporting its errno
+fn io_with_deadline<T: Send + 'static>(
+    timeout: Option<Duration>,
+    f: impl FnOnce() -> std::io::Result<T> + Send + 'static,
+) -> nix::Result<T> {
+    with_deadline(
+        timeout,
+        move || f().map_err(|e| nix::errno::Errno::from_i32(e.raw_os_error().unwrap_or(libc::EIO))),
+        drop,
+    )
+}
+
+/// Whether `path` exists, finding out within `timeout`
+fn exists_within(timeout: Option<Duration>, path: &Path) -> nix::Result<bool> {
+    let path = path.to_owned();
+    with_deadline(timeout, move || Ok(std::fs::metadata(&path).is_ok()), drop)
+}
+
+/// Log FUSE operation `$op` with its arguments if `FsOptions::log_requests` is enabled
+macro_rules! log_request {
+    ($self:ident, $req:expr, $op:literal $(, $name:ident = $val:expr)* $(,)?) => {
+        if $self.opts.log_requests && log::log_enabled!(log::Level::Trace) {
+            log::trace!(
+                concat!($op, " uid={} gid={} pid={}" $(, " ", stringify!($name), "={:?}")*),
+                $req.uid(), $req.gid(), $req.pid() $(, $val)*
+            );
+        }
+    };
+}
+
+macro_rules! nftry {
+    ($e:expr, $reply:ident) => {
+        match $e {
+            Ok(x) => x,
+            Err(e) => return $reply.error(e as i32),
+        }
+    };
+}
+
+/// Reply with extended attribute value or list, following the size probing protocol
+fn xattr_reply(data: &[u8], size: u32, reply: fuser::ReplyXattr) {
+    if size == 0 {
+        reply.size(data.len() as u32)
+    } else if data.len() > size as usize {
+        reply.error(libc::ERANGE)
+    } else {
+        reply.data(data)
+    }
+}
+
+/// Whether `uid`/`gid` may access a file with given attributes, `mask` being a combination of `R_OK`, `W_OK` and `X_OK`.
+/// Root may do anything except executing a file without any execute bits.
+/// `groups` gives supplementary groups, looked up only if they decide.
+fn permits(st: &nix::sys::stat::FileStat, opts: &FsOptions, uid: u32, gid: u32, groups: impl FnOnce() -> Vec<u32>, mask: i32) -> bool {
+    let mode = st.st_mode as i32;
+    if uid == 0 {
+        return mask & libc::X_OK == 0 || mode & 0o111 != 0;
+    }
+    let (owner, group) = opts.report_owner.unwrap_or((st.st_uid, st.st_gid));
+    let granted = if uid == owner {
+        mode >> 6
+    } else if gid == group || groups().contains(&group) {
+        mode >> 3
+    } else {
+        mode
+    };
+    granted & mask & 0o7 == mask
+}
+
+/// Errno for a write to `path` refused by `permits`: EROFS if backing filesystem is read-only anyway, as the kernel checks that first
+fn write_denied_errno(path: &Path) -> i32 {
+    if on_read_only_fs(path) {
+        libc::EROFS
+    } else {
+        libc::EACCES
+    }
+}
+
+/// File type of backing file according to its `st_mode`
+fn file_type(st: &nix::sys::stat::FileStat) -> fuser::FileType {
+    use nix::sys::stat::SFlag;
+    use fuser::FileType;
+    match SFlag::from_bits_truncate(st.st_mode) & SFlag::S_IFMT {
+        SFlag::S_IFDIR => FileType::Directory,
+        SFlag::S_IFLNK => FileType::Symlink,
+        SFlag::S_IFCHR => FileType::CharDevice,
+        SFlag::S_IFBLK => FileType::BlockDevice,
+        SFlag::S_IFIFO => FileType::NamedPipe,
+        SFlag::S_IFSOCK => FileType::Socket,
+        _ => FileType::RegularFile,
+    }
+}
+
+/// Attributes of read-only in-memory `content` that has no backing file
+fn memory_stat(content: &[u8]) -> nix::sys::stat::FileStat {
+    // All zeroes is a valid `stat`
+    let mut st: nix::sys::stat::FileStat = unsafe { std::mem::zeroed() };
+    st.st_mode = libc::S_IFREG | 0o444;
+    st.st_nlink = 1;
+    st.st_size = content.len() as _;
+    st.st_blksize = 4096;
+    st
+}
+
+/// Whether backing file with attributes `st` exceeds `FsOptions::max_file_size`
+fn too_big(st: &nix::sys::stat::FileStat, opts: &FsOptions) -> bool {
+    opts.max_file_size.map_or(false, |max| st.st_size as u64 > max)
+}
+
+/// Size of `file` after `to_crlf` conversion
+fn crlf_size(file: &Path) -> nix::Result<libc::off_t> {
+    to_off_t(to_crlf(&read_file(file)?).len())
+}
+
+// `as usize` conversions of FUSE's `u32` sizes rely on this
+const _: () = assert!(std::mem::size_of::<usize>() >= std::mem::size_of::<u32>());
+
+/// Convert file offset or size for libc calls. `off_t` is only 32-bit on some 32-bit targets (e.g. i686 and armv7 glibc).
+fn to_off_t<T: TryInto<libc::off_t>>(x: T) -> nix::Result<libc::off_t> {
+    x.try_into().map_err(|_| nix::errno::Errno::EOVERFLOW)
+}
+
+/// Index into in-memory content for file offset `offset`; beyond the end if it does not fit `usize`
+fn buf_index(offset: i64) -> usize {
+    usize::try_from(offset.max(0)).unwrap_or(usize::MAX)
+}
+
+/// Read whole regular `file`, reporting errno for replying to FUSE requests
+fn read_file(file: &Path) -> nix::Result<Vec<u8>> {
+    let fd = open_regular(file, OFlag::O_RDONLY | OFlag::O_CLOEXEC, nix::sys::stat::Mode::empty())?;
+    let content = read_all_fd(fd);
+    let _ = nix::unistd::close(fd);
+    content
+}
+
+/// Open backing file `path`, failing with EISDIR or EACCES unless it is a regular file.
+/// It is opened with O_NONBLOCK, so that a FIFO or device planted in backing directory cannot hang the FUSE thread, which is cleared again unless in `oflags`.
+fn open_regular(path: &Path, oflags: OFlag, mode: nix::sys::stat::Mode) -> nix::Result<i32> {
+    use nix::fcntl::FcntlArg;
+    use nix::sys::stat::SFlag;
+    let fd = nix::fcntl::open(path, oflags | OFlag::O_NONBLOCK, mode)?;
+    let checked = nix::sys::stat::fstat(fd)
+        .and_then(|st| match SFlag::from_bits_truncate(st.st_mode) & SFlag::S_IFMT {
+            SFlag::S_IFREG => Ok(()),
+            SFlag::S_IFDIR => Err(nix::errno::Errno::EISDIR),
+            _ => Err(nix::errno::Errno::EACCES),
+        })
+        .and_then(|()| {
+            if oflags.contains(OFlag::O_NONBLOCK) {
+                return Ok(());
+            }
+            let fl = OFlag::from_bits_truncate(nix::fcntl::fcntl(fd, FcntlArg::F_GETFL)?);
+            nix::fcntl::fcntl(fd, FcntlArg::F_SETFL(fl - OFlag::O_NONBLOCK)).map(drop)
+        });
+    match checked {
+        Ok(()) => Ok(fd),
+        Err(e) => {
+            let _ = nix::unistd::close(fd);
+            Err(e)
+        }
+    }
+}
+
+/// Attributes of backing file `path`. Unless `follow_symlinks`, a symlink is not followed and fails with ELOOP, like opening it does.
+fn stat_backing(path: &Path, follow_symlinks: bool) -> nix::Result<nix::sys::stat::FileStat> {
+    use nix::sys::stat::SFlag;
+    if follow_symlinks {
+        return nix::sys::stat::stat(path);
+    }
+    let st = nix::sys::stat::lstat(path)?;
+    if SFlag::from_bits_truncate(st.st_mode) & SFlag::S_IFMT == SFlag::S_IFLNK {
+        return Err(nix::errno::Errno::ELOOP);
+    }
+    Ok(st)
+}
+
+/// Convert LF line endings to CRLF, leaving existing CRLF ones intact
+fn to_crlf(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + data.len() / 16);
+    for (i, &b) in data.iter().enumerate() {
+        if b == b'\n' && (i == 0 || data[i - 1] != b'\r') {
+            out.push(b'\r');
+        }
+        out.push(b);
+    }
+    out
+}
+
+/// Convert CRLF line endings to LF
+fn from_crlf(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    for (i, &b) in data.iter().enumerate() {
+        if b == b'\r' && data.get(i + 1) == Some(&b'\n') {
+            continue;
+        }
+        out.push(b);
+    }
+    out
+}
+
+/// `pwrite` restarted when a signal interrupts it. Interrupted `pwrite` has written nothing, otherwise it would return a short count.
+fn pwrite_retrying(fd: i32, data: &[u8], offset: libc::off_t) -> nix::Result<usize> {
+    retry_eintr(|| nix::sys::uio::pwrite(fd, data, offset))
+}
+
+/// Call `f` until it fails with something other than `EINTR`
+fn retry_eintr<T>(mut f: impl FnMut() -> nix::Result<T>) -> nix::Result<T> {
+    loop {
+        match f() {
+            Err(nix::errno::Errno::EINTR) => continue,
+            x => return x,
+        }
+    }
+}
+
+/// Read all of `fd` from the beginning
+fn read_all_fd(fd: i32) -> nix::Result<Vec<u8>> {
+    let mut content = Vec::new();
+    let mut buf = [0u8; 8192];
+    loop {
+        match nix::sys::uio::pread(fd, &mut buf, to_off_t(content.len())?)? {
+            0 => return Ok(content),
+            n => content.extend_from_slice(&buf[..n]),
+        }
+    }
+}
+
+fn reply_attr(st: &nix::sys::stat::FileStat, ino: u64, opts: &FsOptions, reply: fuser::ReplyAttr) {
+    let (uid, gid) = opts.report_owner.unwrap_or((st.st_uid, st.st_gid));
+    let size = st.st_size as u64 + opts.read_prefix.as_ref().map_or(0, |x| x.len() as u64);
+    reply.attr(
+        &opts.attr_ttl,
+        &fuser::FileAttr {
+            ino,
+            size,
+            blocks: st.st_blocks as u64,
+            atime: SystemTime::UNIX_EPOCH
+                + Duration::new(st.st_atime as u64, st.st_atime_nsec as u32),
+            mtime: SystemTime::UNIX_EPOCH
+                + Duration::new(st.st_mtime as u64, st.st_mtime_nsec as u32),
+            ctime: SystemTime::UNIX_EPOCH
+                + Duration::new(st.st_ctime as u64, st.st_ctime_nsec as u32),
+            crtime: SystemTime::UNIX_EPOCH, // https://github.com/nix-rust/nix/issues/1649
+            kind: file_type(st),
+            perm: (st.st_mode & 0o7777) as u16,
+            nlink: st.st_nlink as u32,
+            uid,
+            gid,
+            rdev: st.st_rdev as u32,
+            blksize: st.st_blksize as u32,
+            flags: 0,
+        },
+    );
+}
+
+impl<F: Mapper> fuser::Filesystem for FileMapperFs<F> {
+    fn destroy(&mut self) {
+        if self.opts.io_accounting {
+            for line in self.io_counters_report(0).lines() {
+                log::info!("I/O bytes (uid read written): {}", line);
+            }
+        }
+    }
+
+    fn getattr(&mut self, _req: &fuser::Request<'_>, ino: u64, reply: fuser::ReplyAttr) {
+        log_request!(self, _req, "getattr", ino = ino);
+        if !self.opts.allowed_ops.allows("getattr") {
+            return reply.error(libc::EPERM);
+        }
+        self.flush_all_writes();
+        if ino == FILE_INO {
+            let bf = match self.get_mapping(_req, false) {
+                Ok(Mapping { path, content: Some(content), .. }) => {
+                    let ino = self.reported_ino(&path);
+                    return reply_attr(&memory_stat(&content), ino, &self.opts, reply);
+                }
+                Ok(x) => x.path,
+                // Unmapped requests may be denied on purpose, so they never get fallback content
+                Err(e) => return reply.error(e as i32),
+            };
+            let path = bf.clone();
+            let ino = self.reported_ino(&bf);
+            let follow_symlinks = self.opts.follow_symlinks;
+            match with_deadline(self.opts.io_timeout, move || stat_backing(&path, follow_symlinks), drop) {
+                Ok(st) => reply_attr(&nftry!(self.served_attr(&bf, st), reply), ino, &self.opts, reply),
+                Err(e) => match self.fallback(&bf) {
+                    Some((_, st)) => reply_attr(&st, ino, &self.opts, reply),
+                    None => reply.error(e as i32),
+                },
+            }
+        } else {
+            reply.error(libc::ENOENT)
+        }
+    }
+
+    fn open(&mut self, _req: &fuser::Request<'_>, ino: u64, flags: i32, reply: fuser::ReplyOpen) {
+        log_request!(self, _req, "open", ino = ino, flags = flags);
+        if !self.opts.allowed_ops.allows_open(flags) {
+            return reply.error(libc::EPERM);
+        }
+        self.flush_all_writes();
+        if ino != FILE_INO {
+            return reply.error(libc::ENOENT);
+        }
+        let write = flags & libc::O_ACCMODE != libc::O_RDONLY || flags & libc::O_TRUNC != 0;
+        let mapping = nftry!(self.get_mapping(_req, write), reply);
+        if write {
+            nftry!(self.check_writable(&mapping, None), reply);
+        }
+        let Mapping { path: bf, content, .. } = mapping;
+        nftry!(self.check_handle_limit(&bf), reply);
+        if let Some(content) = content {
+            let fh = nftry!(self.register_memory_handle(content, &bf, _req.uid()), reply);
+            return reply.opened(fh, self.fopen_flags());
+        }
+
+        let mode = nix::sys::stat::Mode::from_bits_truncate(0o666);
+        let mut oflags = OFlag::from_bits_truncate(flags);
+        if !self.opts.follow_symlinks {
+            oflags |= OFlag::O_NOFOLLOW;
+        }
+        if self.opts.permission_checks && flags & libc::O_TRUNC != 0 {
+            // Truncation happens on open, so it cannot wait for the check on the descriptor below
+            if let Ok(st) = stat_backing(&bf, self.opts.follow_symlinks) {
+                if !self.permits(_req, &st, libc::W_OK) {
+                    return reply.error(write_denied_errno(&bf));
+                }
+            }
+        }
+        let noatime = self.opts.noatime;
+        let path = bf.clone();
+        let open = move || {
+            let mut result = Err(nix::errno::Errno::EPERM);
+            if noatime {
+                result = open_regular(&path, oflags | OFlag::O_NOATIME, mode);
+            }
+            if result == Err(nix::errno::Errno::EPERM) {
+                // Only owner of the file may use O_NOATIME
+                result = open_regular(&path, oflags, mode);
+            }
+            result
+        };
+        let result = with_deadline(self.opts.io_timeout, open, |fd| {
+            let _ = nix::unistd::close(fd);
+        });
+        if let (Some(_), Ok(fd)) = (self.opts.max_file_size, result) {
+            if nix::sys::stat::fstat(fd).map_or(false, |st| too_big(&st, &self.opts)) {
+                let _ = nix::unistd::close(fd);
+                return reply.error(libc::EFBIG);
+            }
+        }
+        if let (true, Ok(fd)) = (self.opts.permission_checks, result) {
+            // Check the file actually opened, not whatever the path pointed to earlier
+            let mask = match flags & libc::O_ACCMODE {
+                libc::O_RDONLY => libc::R_OK,
+                libc::O_WRONLY => libc::W_OK,
+                _ => libc::R_OK | libc::W_OK,
+            };
+            if let Ok(st) = nix::sys::stat::fstat(fd) {
+                if !self.permits(_req, &st, mask) {
+                    let _ = nix::unistd::close(fd);
+                    return reply.error(libc::EACCES);
+                }
+            }
+        }
+        match result {
+            Ok(fh) => {
+                let converted = if self.opts.crlf {
+                    let content = match flags & libc::O_ACCMODE {
+                        _ if flags & libc::O_TRUNC != 0 => Ok(Vec::new()),
+                        // Write-only descriptor cannot be read, convert from a read-only one
+                        libc::O_WRONLY => {
+                            let nofollow = if self.opts.follow_symlinks { OFlag::empty() } else { OFlag::O_NOFOLLOW };
+                            open_regular(&bf, OFlag::O_RDONLY | OFlag::O_CLOEXEC | nofollow, nix::sys::stat::Mode::empty()).and_then(|fd| {
+                                let content = read_all_fd(fd);
+                                let _ = nix::unistd::close(fd);
+                                content
+                            })
+                        }
+                        _ => read_all_fd(fh),
+                    };
+                    match content {
+                        Ok(x) => Some(to_crlf(&x)),
+                        Err(e) => {
+                            let _ = nix::unistd::close(fh);
+                            return reply.error(e as i32);
+                        }
+                    }
+                } else {
+                    None
+                };
+                self.register_handle(fh as u64, &bf, _req.uid());
+                if let Some(h) = self.handles.get_mut(&(fh as u64)) {
+                    h.converted = converted;
+                }
+                reply.opened(fh as u64, self.fopen_flags())
+            }
+            Err(e) => {
+                if !write {
+                    if let Some((content, _)) = self.fallback(&bf) {
+                        let fh = nftry!(self.register_memory_handle(content, &bf, _req.uid()), reply);
+                        return reply.opened(fh, self.fopen_flags());
+                    }
+                }
+                reply.error(e as i32)
+            }
+        }
+    }
+
+    /// Called on each `close(2)`, so that errors of deferred writes reach the application
+    fn flush(&mut self, _req: &fuser::Request<'_>, _ino: u64, _fh: u64, _lock_owner: u64, reply: fuser::ReplyEmpty) {
+        log_request!(self, _req, "flush", ino = _ino, fh = _fh);
+        match self.flush_writes(_fh) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e as i32),
+        }
+    }
+
+    fn release(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        _ino: u64,
+        _fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: fuser::ReplyEmpty,
+    ) {
+        log_request!(self, _req, "release", ino = _ino, fh = _fh);
+        let flushed = self.flush_writes(_fh);
+        match self.unregister_handle(_fh).and(flushed) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e as i32),
+        }
+    }
+
+    fn fsync(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        _ino: u64,
+        _fh: u64,
+        datasync: bool,
+        reply: fuser::ReplyEmpty,
+    ) {
+        log_request!(self, _req, "fsync", ino = _ino, fh = _fh, datasync = datasync);
+        if !self.opts.allowed_ops.allows("fsync") {
+            return reply.error(libc::EPERM);
+        }
+        nftry!(self.check_handle(_fh), reply);
+        if self.memory_content(_fh).is_some() {
+            return reply.ok();
+        }
+        nftry!(self.flush_writes(_fh), reply);
+        self.mirror_handle(_fh);
+        let result = if datasync {
+            self.on_backing_fd(_fh, nix::unistd::fdatasync)
+        } else {
+            self.on_backing_fd(_fh, nix::unistd::fsync)
+        };
+        match result {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e as i32),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        _ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: fuser::ReplyData,
+    ) {
+        log_request!(self, _req, "read", ino = _ino, fh = _fh, offset = offset, size = size);
+        if !self.opts.allowed_ops.allows("read") {
+            return reply.error(libc::EPERM);
+        }
+        self.flush_all_writes();
+        size = size.min(READ_MAX_BYTES);
+        if let Some(max) = self.opts.max_file_size {
+            // The file may have grown since it was opened
+            let left = max.saturating_sub(offset.max(0) as u64);
+            size = size.min(left.min(u32::MAX as u64) as u32);
+        }
+        let prefix = match self.opts.read_prefix {
+            Some(ref x) => x.clone(),
+            None => {
+                let buf = nftry!(self.read_backing(_fh, offset, size), reply);
+                self.account_io(_fh, buf.len(), 0);
+                return reply.data(&buf);
+            }
+        };
+
+        // File content is shifted by the prefix length
+        let offset = offset.max(0) as u64;
+        let plen = prefix.len() as u64;
+        let mut buf = Vec::with_capacity(size as usize);
+        if offset < plen {
+            let end = (offset + size as u64).min(plen);
+            buf.extend_from_slice(&prefix[offset as usize..end as usize]);
+        }
+        let rest = size - buf.len() as u32;
+        if rest > 0 {
+            let file_offset = offset.saturating_sub(plen) as i64;
+            buf.extend(nftry!(self.read_backing(_fh, file_offset, rest), reply));
+        }
+        self.account_io(_fh, buf.len(), 0);
+        reply.data(&buf)
+    }
+
+    fn write(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        _ino: u64,
+        _fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: fuser::ReplyWrite,
+    ) {
+        log_request!(self, _req, "write", ino = _ino, fh = _fh, offset = offset, size = data.len());
+        if !self.opts.allowed_ops.allows("write") {
+            return reply.error(libc::EPERM);
+        }
+        if self.opts.read_prefix.is_some() || self.memory_content(_fh).is_some() {
+            return reply.error(libc::EROFS);
+        }
+        nftry!(self.check_handle(_fh), reply);
+        let converted = self.handles.get_mut(&_fh).and_then(|h| h.converted.as_mut().map(|x| (x, &mut h.converted_dirty)));
+        let ret = if let Some((content, dirty)) = converted {
+            let start = nftry!(usize::try_from(offset.max(0)).map_err(|_| nix::errno::Errno::EFBIG), reply);
+            let end = nftry!(start.checked_add(data.len()).ok_or(nix::errno::Errno::EFBIG), reply);
+            *dirty = true;
+            if content.len() < end {
+                content.resize(end, 0);
+            }
+            content[start..end].copy_from_slice(data);
+            data.len()
+        } else if self.opts.coalesce_writes {
+            nftry!(self.buffer_write(_fh, data, offset), reply)
+        } else {
+            nftry!(self.pwrite_backing(_fh, data, offset), reply)
+        };
+        self.account_io(_fh, 0, ret);
+        self.mark_dirty(_fh);
+        // `ret` is at most `data.len()`, which is limited by FUSE's `u32` request size
+        reply.written(ret as u32)
+    }
+
+    fn setattr(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        ino: u64,
+        _mode: Option<u32>,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
+        _size: Option<u64>,
+        _atime: Option<fuser::TimeOrNow>,
+        _mtime: Option<fuser::TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: fuser::ReplyAttr,
+    ) {
+        log_request!(self, _req, "setattr", ino = ino, fh = _fh, size = _size);
+        if !self.opts.allowed_ops.allows("setattr") {
+            return reply.error(libc::EPERM);
+        }
+        self.flush_all_writes();
+        if ino != FILE_INO {
+            return reply.error(libc::ENOENT);
+        }
+
+        let mapping = nftry!(self.get_mapping(_req, _size.is_some()), reply);
+
+        // Like the kernel, report read-only file before missing permissions
+        if _size.is_some() {
+            nftry!(self.check_writable(&mapping, _fh), reply);
+        }
+        let Mapping { path: bf, content, .. } = mapping;
+        if let Some(content) = content {
+            let ino = self.reported_ino(&bf);
+            return reply_attr(&memory_stat(&content), ino, &self.opts, reply);
+        }
+
+        if self.opts.permission_checks && _req.uid() != 0 {
+            if _mode.is_some() || _uid.is_some() || _gid.is_some() {
+                return reply.error(libc::EPERM);
+            }
+            if _size.is_some() && _fh.is_none() {
+                let st = nftry!(stat_backing(&bf, self.opts.follow_symlinks), reply);
+                if !self.permits(_req, &st, libc::W_OK) {
+                    return reply.error(write_denied_errno(&bf));
+                }
+            }
+        }
+
+        if let Some(size) = _size {
+            nftry!(self.truncate_backing(&bf, size, _fh), reply);
+        }
+        let st = nftry!(self.attr_after_setattr(&bf, _fh), reply);
+        let ino = self.reported_ino(&bf);
+        reply_attr(&st, ino, &self.opts, reply);
+    }
+
+    fn getxattr(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+        reply: fuser::ReplyXattr,
+    ) {
+        log_request!(self, _req, "getxattr", ino = ino, name = name, size = size);
+        if !self.opts.allowed_ops.allows("getxattr") {
+            return reply.error(libc::EPERM);
+        }
+        if ino != FILE_INO {
+            return reply.error(libc::ENOENT);
+        }
+        if self.opts.virtual_etag && name == ETAG_XATTR {
+            self.flush_all_writes();
+            let bf = nftry!(self.get_backing_file(_req, false), reply);
+            let st = nftry!(stat_backing(&bf, self.opts.follow_symlinks), reply);
+            let etag = format!(
+                "{:x}-{}.{:09}-{}",
+                st.st_ino, st.st_mtime, st.st_mtime_nsec, st.st_size
+            );
+            return xattr_reply(etag.as_bytes(), size, reply);
+        }
+        if name == SOURCE_XATTR {
+            let mapping = nftry!(self.get_mapping(_req, false), reply);
+            return xattr_reply(mapping.source.as_str().as_bytes(), size, reply);
+        }
+        if self.opts.io_accounting && name == IO_BYTES_XATTR {
+            let report = self.io_counters_report(_req.uid());
+            return xattr_reply(report.as_bytes(), size, reply);
+        }
+        if self.opts.debug_xattrs && name == BACKING_PATH_XATTR {
+            let bf = nftry!(self.get_backing_file(_req, false), reply);
+            return xattr_reply(bf.as_os_str().as_bytes(), size, reply);
+        }
+        reply.error(libc::ENODATA)
+    }
+
+    fn access(&mut self, _req: &fuser::Request<'_>, ino: u64, mask: i32, reply: fuser::ReplyEmpty) {
+        log_request!(self, _req, "access", ino = ino, mask = mask);
+        if !self.opts.allowed_ops.allows("access") {
+            return reply.error(libc::EPERM);
+        }
+        if ino != FILE_INO {
+            return reply.error(libc::ENOENT);
+        }
+        if !self.opts.permission_checks {
+            return reply.ok();
+        }
+        let mapping = nftry!(self.get_mapping(_req, mask & libc::W_OK != 0), reply);
+        if mask & libc::W_OK != 0 {
+            nftry!(self.check_writable(&mapping, None), reply);
+        }
+        let Mapping { path: bf, content, .. } = mapping;
+        let st = match content {
+            Some(content) => memory_stat(&content),
+            None => nftry!(stat_backing(&bf, self.opts.follow_symlinks), reply),
+        };
+        if self.permits(_req, &st, mask) {
+            reply.ok()
+        } else if mask & libc::W_OK != 0 {
+            reply.error(write_denied_errno(&bf))
+        } else {
+            reply.error(libc::EACCES)
+        }
+    }
+
+    fn listxattr(&mut self, _req: &fuser::Request<'_>, ino: u64, size: u32, reply: fuser::ReplyXattr) {
+        log_request!(self, _req, "listxattr", ino = ino, size = size);
+        if !self.opts.allowed_ops.allows("listxattr") {
+            return reply.error(libc::EPERM);
+        }
+        if ino != FILE_INO {
+            return reply.error(libc::ENOENT);
+        }
+        let mut list = Vec::new();
+        list.extend_from_slice(SOURCE_XATTR.as_bytes());
+        list.push(0);
+        if self.opts.virtual_etag {
+            list.extend_from_slice(ETAG_XATTR.as_bytes());
+            list.push(0);
+        }
+        if self.opts.io_accounting {
+            list.extend_from_slice(IO_BYTES_XATTR.as_bytes());
+            list.push(0);
+        }
+        if self.opts.debug_xattrs {
+            list.extend_from_slice(BACKING_PATH_XATTR.as_bytes());
+            list.push(0);
+        }
+        xattr_reply(&list, size, reply)
+    }
+}
+
+/// Find out whether `dir` treats file names differing only in case as the same file, by creating a probe file there
+fn is_case_insensitive(dir: &Path) -> std::io::Result<bool> {
+    let lower = dir.join(format!(".resolvconffs-case-probe-{}", std::process::id()));
+    let upper = dir.join(format!(".RESOLVCONFFS-CASE-PROBE-{}", std::process::id()));
+    std::fs::File::create(&lower)?;
+    let result = std::fs::symlink_metadata(&upper).is_ok();
+    std::fs::remove_file(&lower)?;
+    Ok(result)
+}
+
+/// Check that `ext` is usable in `build_target_path`: no path separators, `..`, whitespace or control characters
+fn validate_extension(ext: &OsStr) -> std::io::Result<()> {
+    let bytes = ext.as_bytes();
+    if bytes.contains(&b'/') || bytes.windows(2).any(|x| x == b"..") || bytes.iter().any(|x| x.is_ascii_whitespace() || x.is_ascii_control()) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Invalid extension {:?}, it should be a plain file name suffix like `conf`", ext),
+        ));
+    }
+    Ok(())
+}
+
+/// Build `<dir>/<id>.<ext>` path. Leading dot in `ext` is optional, empty `ext` means no extension.
+/// Unlike with `Path::set_extension`, dots inside `id` are left intact.
+fn build_target_path(dir: &Path, id: &str, ext: &OsStr) -> PathBuf {
+    let ext = ext.as_bytes();
+    let ext = ext.strip_prefix(b".").unwrap_or(ext);
+    let mut name = OsString::with_capacity(id.len() + 1 + ext.len());
+    name.push(id);
+    if !ext.is_empty() {
+        name.push(".");
+        name.push(OsStr::from_bytes(ext));
+    }
+    dir.join(name)
+}
+
+/// Whether resolvconffs itself may open `file` for writing
+fn is_writable(file: &Path) -> bool {
+    nix::unistd::access(file, nix::unistd::AccessFlags::W_OK).is_ok()
+}
+
+/// Whether `dir` is a mounted procfs rather than a plain directory mimicking it
+fn is_procfs(dir: &Path) -> bool {
+    nix::sys::statfs::statfs(dir).map_or(false, |x| x.filesystem_type() == nix::sys::statfs::PROC_SUPER_MAGIC)
+}
+
+/// `build_target_path` for the first of `exts` whose file exists, and index of that extension.
+/// If none exists, uses `exts[preferred]`. Each existence check must finish within `timeout`.
+fn find_target_path(
+    dir: &Path,
+    id: &str,
+    exts: &[OsString],
+    preferred: usize,
+    timeout: Option<Duration>,
+) -> nix::Result<(PathBuf, usize)> {
+    for (i, ext) in exts.iter().enumerate() {
+        let path = build_target_path(dir, id, ext);
+        if exts.len() == 1 || exists_within(timeout, &path)? {
+            return Ok((path, i));
+        }
+    }
+    Ok((build_target_path(dir, id, &exts[preferred]), preferred))
+}
+
+/// Find out whether procfs mounted at `procfs` restricts visibility of other users' processes.
+/// Returns the offending `hidepid=` mount option.
+fn procfs_hidepid(procfs: &Path) -> Option<String> {
+    let mountinfo = std::fs::read_to_string(procfs.join("self/mountinfo")).ok()?;
+    let procfs = std::fs::canonicalize(procfs).ok()?;
+    // 23 28 0:22 / /proc rw,relatime - proc proc rw,hidepid=2
+    let mut hidepid = None;
+    for line in mountinfo.lines() {
+        let (mount, superblock) = match line.split_once(" - ") {
+            Some(x) => x,
+            None => continue,
+        };
+        if mount.split(' ').nth(4).map(Path::new) != Some(procfs.as_path()) {
+            continue;
+        }
+        let mut sb = superblock.split(' ');
+        if sb.next() != Some("proc") {
+            continue;
+        }
+        // Later mounts over the same mountpoint overshadow earlier ones
+        hidepid = sb
+            .nth(1)
+            .unwrap_or("")
+            .split(',')
+            .find(|x| x.starts_with("hidepid=") && !matches!(*x, "hidepid=0" | "hidepid=off"))
+            .map(str::to_owned);
+    }
+    hidepid
+}
+
+const NETNS_NAMES_TTL: Duration = Duration::from_secs(5);
+
+/// Reverse mapping from netns inode numbers to names of `ip netns` bind mounts, rescanned at most every `NETNS_NAMES_TTL`
+struct NetnsNames {
+    dir: PathBuf,
+    scanned_at: Option<Instant>,
+    names: HashMap<u64, String>,
+}
+
+impl NetnsNames {
+    fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            scanned_at: None,
+            names: HashMap::new(),
+        }
+    }
+
+    fn lookup(&mut self, id: &str) -> Option<String> {
+        let ino: u64 = id.parse().ok()?;
+        if self.scanned_at.map_or(true, |t| t.elapsed() >= NETNS_NAMES_TTL) {
+            self.rescan();
+        }
+        self.names.get(&ino).cloned()
+    }
+
+    fn rescan(&mut self) {
+        use std::os::unix::fs::MetadataExt;
+        self.scanned_at = Some(Instant::now());
+        self.names.clear();
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(x) => x,
+            Err(e) => {
+                log::debug!("Cannot scan {:?}: {}", self.dir, e);
+                return;
+            }
+        };
+        for entry in entries.flatten() {
+            if let (Ok(meta), Some(name)) = (std::fs::metadata(entry.path()), entry.file_name().to_str()) {
+                self.names.insert(meta.ino(), name.to_owned());
+            }
+        }
+    }
+}
+
+struct NetnsMapper {
+    backing_directory: PathBuf,
+    /// Extensions to try in order, at least one
+    extensions: Vec<OsString>,
+    default_file: Option<PathBuf>,
+    /// Text to populate missing files with if there is no default file
+    default_content: Option<Arc<[u8]>>,
+    /// Per-uid default files, taking precedence over `default_file`
+    default_files_per_uid: HashMap<u32, PathBuf>,
+    max_default_copies: Option<u64>,
+    default_copy_total_bytes: Option<u64>,
+    /// Give files populated from a default file to the requestor's uid and gid
+    own_created_files: bool,
+    /// Number of files populated from a default file so far
+    default_copies: u64,
+    /// Total size of files populated from a default file so far
+    default_copied_bytes: u64,
+    /// Files populated from a default file or content, at most `POPULATED_MAX_ENTRIES`.
+    /// Files changed since then are considered requestor's own files.
+    populated: HashMap<PathBuf, Populated>,
+    procfs: PathBuf,
+    /// Look up `backing_directory` inside requestor's mount namespace, via `<procfs>/<pid>/root`
+    requestor_root: bool,
+    /// Writable layer over read-only `backing_directory`
+    upper_dir: Option<PathBuf>,
+    /// Copy files up to `upper_dir` only if they are not writable in `backing_directory`
+    cow_on_write_open: bool,
+    /// Put files into `shard_name` subdirectories
+    shard: bool,
+    /// Use thread group leader's pid instead of requestor's one
+    use_tgid: bool,
+    /// Content of `<procfs>/1/ns/net` link, if requests from there are read-only
+    readonly_netns: Option<String>,
+    /// Identifier to use for all requests instead of requestor's netns
+    force_id: Option<String>,
+    /// Content of resolvconffs's own `ns/net` link and the file to serve there
+    host: Option<(String, PathBuf)>,
+    key_source: KeySource,
+    /// Prepended to identifiers in backing file names
+    id_prefix: String,
+    /// Appended to identifiers in backing file names, before the extension
+    id_suffix: String,
+    /// Suffix identifiers with start time of the process
+    key_include_starttime: bool,
+    /// Refuse identities not listed in `policy`
+    deny_by_default: bool,
+    max_id_len: usize,
+    policy: Option<Policy>,
+    id_map: HashMap<String, String>,
+    netns_names: Option<NetnsNames>,
+    audit_log: Option<AuditLog>,
+    on_new_netns: Option<NewIdHook>,
+    /// Deadline for backing file syscalls, see `with_deadline`
+    io_timeout: Option<Duration>,
+    log_dedup: LogDedup,
+}
+
+/// Copy all of `src` to `dst`, retrying on `EINTR` and continuing after short writes
+fn copy_fully(src: &mut impl std::io::Read, dst: &mut impl std::io::Write) -> std::io::Result<u64> {
+    let mut buf = [0u8; 8192];
+    let mut total = 0;
+    loop {
+        let len = match src.read(&mut buf) {
+            Ok(0) => return Ok(total),
+            Ok(x) => x,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        let mut chunk = &buf[..len];
+        while !chunk.is_empty() {
+            match dst.write(chunk) {
+                Ok(0) => return Err(std::io::ErrorKind::WriteZero.into()),
+                Ok(x) => chunk = &chunk[x..],
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        total += len as u64;
+    }
+}
+
+/// Copy `src` to `dst` via a temporary file in `dst`'s directory, so `dst` never appears partially written.
+/// The temporary file is fsynced before renaming, so `dst` is not left empty after a crash.
+fn copy_atomically(src: &Path, dst: &Path) -> std::io::Result<u64> {
+    let mut input = std::fs::File::open(src)?;
+    let permissions = input.metadata()?.permissions();
+    replace_atomically(dst, |output| {
+        output.set_permissions(permissions)?;
+        copy_fully(&mut input, output)
+    })
+}
+
+/// Attempts at finding an unused temporary file name in `replace_atomically`
+const TMP_NAME_ATTEMPTS: u32 = 16;
+
+/// Create `dst` with `fill`ing a temporary file and renaming it over `dst` after fsync.
+/// The temporary file is created exclusively and without following symlinks,
+/// as the backing directory may be writable by users who could plant one under a predictable name.
+fn replace_atomically(
+    dst: &Path,
+    fill: impl FnOnce(&mut std::fs::File) -> std::io::Result<u64>,
+) -> std::io::Result<u64> {
+    use std::os::unix::fs::OpenOptionsExt;
+    static COUNTER: std::sync::atomic::AtomicU32 = std::sync::atomic::AtomicU32::new(0);
+    let mut attempt = 0;
+    let (tmp, mut output) = loop {
+        let n = COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let mut tmpname = OsString::from(".resolvconffs-tmp-");
+        tmpname.push(dst.file_name().unwrap_or_default());
+        tmpname.push(format!(".{}.{}", std::process::id(), n));
+        let tmp = dst.with_file_name(tmpname);
+        match std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(&tmp)
+        {
+            Ok(x) => break (tmp, x),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists && attempt + 1 < TMP_NAME_ATTEMPTS => attempt += 1,
+            Err(e) => return Err(e),
+        }
+    };
+    let replace = || -> std::io::Result<u64> {
+        let len = fill(&mut output)?;
+        output.sync_all()?;
+        std::fs::rename(&tmp, dst)?;
+        Ok(len)
+    };
+    let result = replace();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    result
+}
+
+/// What to populate missing backing files from
+#[derive(Clone)]
+enum Template {
+    File(PathBuf),
+    Content(Arc<[u8]>),
+}
+
+impl Template {
+    fn source(&self) -> Source {
+        match self {
+            Template::File(_) => Source::Default,
+            Template::Content(_) => Source::Generated,
+        }
+    }
+
+    fn len(&self) -> u64 {
+        match self {
+            Template::File(x) => std::fs::metadata(x).map(|x| x.len()).unwrap_or(0),
+            Template::Content(x) => x.len() as u64,
+        }
+    }
+
+    /// `fnv1a` of the content this template would write
+    fn hash(&self) -> std::io::Result<u64> {
+        match self {
+            Template::File(x) => Ok(fnv1a(&std::fs::read(x)?)),
+            Template::Content(x) => Ok(fnv1a(x)),
+        }
+    }
+
+    fn write_to(&self, target: &Path) -> std::io::Result<u64> {
+        match self {
+            Template::File(x) => copy_atomically(x, target),
+            Template::Content(x) => replace_atomically(target, |output| {
+                copy_fully(&mut &x[..], output)
+            }),
+        }
+    }
+}
+
+/// Upper bound of files tracked in `NetnsMapper::populated`. Further copies are served as requestor's own files and not refreshed.
+const POPULATED_MAX_ENTRIES: usize = 65536;
+
+/// File populated from `template` by `NetnsMapper::copy_default`, as it was right after populating
+struct Populated {
+    /// Identify the file cheaply as long as it is not touched
+    ino: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+    /// `fnv1a` of its content, deciding whether it is still unmodified once it is touched
+    hash: u64,
+    template: Template,
+}
+
+impl Populated {
+    /// Record `file`, just written from `template`
+    fn of(file: &Path, template: Template) -> std::io::Result<Populated> {
+        use std::os::unix::fs::MetadataExt;
+        let m = std::fs::metadata(file)?;
+        Ok(Populated {
+            ino: m.ino(),
+            mtime: m.mtime(),
+            mtime_nsec: m.mtime_nsec(),
+            hash: fnv1a(&std::fs::read(file)?),
+            template,
+        })
+    }
+}
+
+impl std::fmt::Display for Template {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Template::File(x) => write!(f, "{:?}", x),
+            Template::Content(_) => write!(f, "--default-content"),
+        }
+    }
+}
+
+/// Subdirectory name for `id` in `--shard` mode: low byte of 32-bit FNV-1a hash of the identifier, as two lowercase hex digits
+fn shard_name(id: &str) -> String {
+    let mut hash: u32 = 0x811c9dc5;
+    for b in id.bytes() {
+        hash ^= b as u32;
+        hash = hash.wrapping_mul(0x01000193);
+    }
+    format!("{:02x}", hash & 0xff)
+}
+
+/// Where `NetnsMapper` takes identifier of requesting process from
+#[derive(Clone, Debug)]
+enum KeySource {
+    /// Inode number of network namespace, `<procfs>/<pid>/ns/net`
+    Netns,
+    /// Profile name written to `<dir>/<pid>` by an orchestrator
+    PidFile(PathBuf),
+    /// Process name from `<procfs>/<pid>/comm`
+    Comm,
+}
+
+impl std::str::FromStr for KeySource {
+    type Err = std::io::Error;
+
+    fn from_str(x: &str) -> std::io::Result<KeySource> {
+        match x.split_once(':') {
+            None if x == "netns" => Ok(KeySource::Netns),
+            None if x == "comm" => Ok(KeySource::Comm),
+            Some(("pidfile", dir)) if !dir.is_empty() => Ok(KeySource::PidFile(dir.into())),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid key source {:?}, expected `netns`, `pidfile:<dir>` or `comm`", x),
+            )),
+        }
+    }
+}
+
+/// `<procfs>/<pid>` opened with `O_PATH`, so that all reads about a process go to the same directory.
+/// If the process exits and its pid gets reused meanwhile, reads fail instead of describing the new process.
+struct PidDir {
+    pid: u32,
+    path: PathBuf,
+    fd: std::os::unix::io::RawFd,
+}
+
+impl PidDir {
+    fn open(procfs: &Path, pid: u32) -> nix::Result<PidDir> {
+        let path = procfs.join(format!("{}", pid));
+        let fd = nix::fcntl::open(&path, OFlag::O_PATH | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC, nix::sys::stat::Mode::empty())?;
+        Ok(PidDir { pid, path, fd })
+    }
+
+    /// Full path of `name` inside, for messages
+    fn path_of(&self, name: &str) -> PathBuf {
+        self.path.join(name)
+    }
+
+    fn read_link(&self, name: &str) -> nix::Result<OsString> {
+        nix::fcntl::readlinkat(self.fd, name)
+    }
+
+    fn read_to_string(&self, name: &str) -> nix::Result<String> {
+        let fd = nix::fcntl::openat(self.fd, name, OFlag::O_RDONLY | OFlag::O_CLOEXEC, nix::sys::stat::Mode::empty())?;
+        let content = read_all_fd(fd);
+        let _ = nix::unistd::close(fd);
+        String::from_utf8(content?).map_err(|_| nix::errno::Errno::EINVAL)
+    }
+
+    /// Start time of the process: `read_starttime` clock ticks after `btime` of `<procfs>/stat`.
+    /// Mtime of the directory is not used, as procfs sets it to when the directory was first looked up rather than to process start.
+    fn started(&self) -> Option<SystemTime> {
+        let ticks = read_starttime(self)?;
+        let stat = std::fs::read_to_string(self.path.parent()?.join("stat")).ok()?;
+        let btime: u64 = stat.lines().find_map(|x| x.strip_prefix("btime "))?.trim().parse().ok()?;
+        let hz = nix::unistd::sysconf(nix::unistd::SysconfVar::CLK_TCK).ok().flatten().filter(|x| *x > 0)? as u64;
+        let since_boot = Duration::from_secs(ticks / hz) + Duration::from_nanos(ticks % hz * 1_000_000_000 / hz);
+        Some(SystemTime::UNIX_EPOCH + Duration::from_secs(btime) + since_boot)
+    }
+}
+
+impl Drop for PidDir {
+    fn drop(&mut self) {
+        let _ = nix::unistd::close(self.fd);
+    }
+}
+
+/// Get thread group id of the process from `<procfs>/<pid>/status`.
+fn read_tgid(dir: &PidDir) -> Option<u32> {
+    let status = dir.read_to_string("status").ok()?;
+    status
+        .lines()
+        .find_map(|x| x.strip_prefix("Tgid:"))
+        .and_then(|x| x.trim().parse().ok())
+}
+
+/// Get supplementary groups of the process from `Groups:` of `<procfs>/<pid>/status`
+fn read_groups(dir: &PidDir) -> Option<Vec<u32>> {
+    let status = dir.read_to_string("status").ok()?;
+    let groups = status.lines().find_map(|x| x.strip_prefix("Groups:"))?;
+    groups.split_whitespace().map(|x| x.parse().ok()).collect()
+}
+
+/// Start time of the process in clock ticks since boot, field 22 of `<procfs>/<pid>/stat`
+fn read_starttime(dir: &PidDir) -> Option<u64> {
+    let stat = dir.read_to_string("stat").ok()?;
+    // Process name in the second field may contain spaces and parentheses, but is followed by the last `)`
+    let (_, rest) = stat.rsplit_once(')')?;
+    rest.split_whitespace().nth(19)?.parse().ok()
+}
+
+/// Read `--id-map-file` content. Empty lines and lines starting with `#` are ignored.
+fn load_id_map(path: &Path) -> std::io::Result<HashMap<String, String>> {
+    let content = std::fs::read_to_string(path)?;
+    let mut id_map = HashMap::new();
+    for (n, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next(), words.next()) {
+            (Some(id), Some(name), None)
+                if !name.contains('/') && name != "." && name != ".." =>
+            {
+                id_map.insert(id.to_owned(), name.to_owned());
+            }
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("{:?} line {}: expected `<id> <name>` without slashes in name", path, n + 1),
+                ))
+            }
+        }
+    }
+    Ok(id_map)
+}
+
+/// Set by SIGHUP handler, asking to reload files like `--policy-file` before serving next request.
+/// Reloading happens on the FUSE session thread between requests, so no request sees a half-reloaded state.
+static RELOAD_REQUESTED: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+extern "C" fn on_sighup(_: libc::c_int) {
+    RELOAD_REQUESTED.store(true, std::sync::atomic::Ordering::Relaxed);
+}
+
+fn install_sighup_handler() -> nix::Result<()> {
+    use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+    let action = SigAction::new(SigHandler::Handler(on_sighup), SaFlags::SA_RESTART, SigSet::empty());
+    unsafe { sigaction(Signal::SIGHUP, &action) }.map(|_| ())
+}
+
+/// Parse `ro` or `rw`, returning whether it means read-only
+fn parse_policy_word(x: &str) -> std::io::Result<bool> {
+    match x {
+        "ro" => Ok(true),
+        "rw" => Ok(false),
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Invalid policy {:?}, expected `ro` or `rw`", x),
+        )),
+    }
+}
+
+/// Content of `--policy-file`: which identifiers and uids get read-only access
+struct Policy {
+    path: PathBuf,
+    default_read_only: bool,
+    ids: HashMap<String, bool>,
+    uids: HashMap<u32, bool>,
+}
+
+impl Policy {
+    /// Read policy file. Empty lines and lines starting with `#` are ignored.
+    fn load(path: &Path, default_read_only: bool) -> std::io::Result<Policy> {
+        let content = std::fs::read_to_string(path)?;
+        let mut policy = Policy {
+            path: path.to_owned(),
+            default_read_only,
+            ids: HashMap::new(),
+            uids: HashMap::new(),
+        };
+        for (n, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut words = line.split_whitespace();
+            let (key, read_only) = match (words.next(), words.next().map(parse_policy_word), words.next()) {
+                (Some(key), Some(Ok(read_only)), None) => (key, read_only),
+                _ => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("{:?} line {}: expected `<id> ro|rw` or `uid:<uid> ro|rw`", path, n + 1),
+                    ))
+                }
+            };
+            match key.strip_prefix("uid:").map(str::parse) {
+                Some(Ok(uid)) => {
+                    policy.uids.insert(uid, read_only);
+                }
+                Some(Err(_)) => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("{:?} line {}: invalid uid", path, n + 1),
+                    ))
+                }
+                None => {
+                    policy.ids.insert(key.to_owned(), read_only);
+                }
+            }
+        }
+        Ok(policy)
+    }
+
+    /// Whether there is an entry for `id` or `uid`
+    fn lists(&self, id: &str, uid: u32) -> bool {
+        self.uids.contains_key(&uid) || self.ids.contains_key(id)
+    }
+
+    /// Uid entries take precedence over identifier ones
+    fn read_only(&self, id: &str, uid: u32) -> bool {
+        self.uids
+            .get(&uid)
+            .or_else(|| self.ids.get(id))
+            .copied()
+            .unwrap_or(self.default_read_only)
+    }
+}
+
+/// Identifiers remembered by `NewIdHook`; least recently seen ones are forgotten beyond that, so their hook may run again
+const NEW_ID_HOOK_MAX_SEEN: usize = 4096;
+/// `--on-new-netns` processes allowed to run at the same time. Identifiers seen while at the limit are retried on their next request.
+const NEW_ID_HOOK_MAX_RUNNING: usize = 8;
+
+/// `--on-new-netns` program runner
+struct NewIdHook {
+    program: PathBuf,
+    /// Identifier and when it was last seen
+    seen: HashMap<String, Instant>,
+    running: Vec<std::process::Child>,
+}
+
+impl NewIdHook {
+    fn new(program: PathBuf) -> NewIdHook {
+        NewIdHook {
+            program,
+            seen: HashMap::new(),
+            running: Vec::new(),
+        }
+    }
+
+    /// Run the program for `id` unless it was seen before
+    fn notify(&mut self, id: &str) {
+        let now = Instant::now();
+        if let Some(t) = self.seen.get_mut(id) {
+            *t = now;
+            return;
+        }
+        self.running = std::mem::take(&mut self.running)
+            .into_iter()
+            .filter_map(|mut x| match x.try_wait() {
+                Ok(Some(_)) => None,
+                _ => Some(x),
+            })
+            .collect();
+        if self.running.len() >= NEW_ID_HOOK_MAX_RUNNING {
+            log::debug!("Not running {:?} for {:?} yet: {} instances already running", self.program, id, self.running.len());
+            return;
+        }
+        match std::process::Command::new(&self.program)
+            .arg(id)
+            .stdin(std::process::Stdio::null())
+            .spawn()
+        {
+            Ok(child) => self.running.push(child),
+            Err(e) => log::warn!("Cannot run {:?} for {:?}: {}", self.program, id, e),
+        }
+        if self.seen.len() >= NEW_ID_HOOK_MAX_SEEN {
+            let oldest = self.seen.iter().min_by_key(|(_, t)| **t).map(|(id, _)| id.clone());
+            if let Some(oldest) = oldest {
+                self.seen.remove(&oldest);
+            }
+        }
+        self.seen.insert(id.to_owned(), now);
+    }
+}
+
+/// Audit log entries are fsync'ed at most this often
+const AUDIT_LOG_SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Upper bound of identifiers remembered by `AuditLog`. When reached, it starts over, recording each identifier again.
+const AUDIT_LOG_MAX_IDS: usize = 65536;
+
+/// `--audit-log` file. Each line is written out right away; fsync happens every `AUDIT_LOG_SYNC_INTERVAL` and on drop.
+struct AuditLog {
+    path: PathBuf,
+    file: std::fs::File,
+    synced_at: Instant,
+    unsynced: bool,
+    /// Path last recorded for each identifier and uid, as mappings also depend on the uid
+    recorded: HashMap<(String, u32), PathBuf>,
+}
+
+impl AuditLog {
+    fn open(path: &Path) -> std::io::Result<AuditLog> {
+        use std::os::unix::fs::OpenOptionsExt;
+        let file = std::fs::OpenOptions::new().append(true).create(true).mode(0o600).open(path)?;
+        Ok(AuditLog {
+            path: path.to_owned(),
+            file,
+            synced_at: Instant::now(),
+            unsynced: false,
+            recorded: HashMap::new(),
+        })
+    }
+
+    /// Switch to a new file at the same path after log rotation moved the old one away.
+    /// The new file records all identifiers anew, so that it does not depend on rotated away ones.
+    fn reopen(&mut self) -> std::io::Result<()> {
+        let _ = self.file.sync_all();
+        *self = AuditLog::open(&self.path)?;
+        Ok(())
+    }
+
+    /// Record mapping of `rq` to `path`, unless identifier `id` was mapped to `path` for this uid last time too
+    fn record(&mut self, rq: UidGidPid, id: &str, path: &Path) -> std::io::Result<()> {
+        use std::io::Write;
+        let key = (id.to_owned(), rq.uid);
+        if self.recorded.get(&key).map(PathBuf::as_path) == Some(path) {
+            return Ok(());
+        }
+        let now = SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+        let line = format!(
+            "{}.{:03} uid={} gid={} pid={} id={:?} path={:?}\n",
+            now.as_secs(),
+            now.subsec_millis(),
+            rq.uid,
+            rq.gid,
+            rq.pid,
+            id,
+            path
+        );
+        // Single write of the whole line, so that lines are not interleaved or torn on rotation
+        self.file.write_all(line.as_bytes())?;
+        self.unsynced = true;
+        if self.recorded.len() >= AUDIT_LOG_MAX_IDS {
+            self.recorded.clear();
+        }
+        self.recorded.insert(key, path.to_owned());
+        if self.synced_at.elapsed() >= AUDIT_LOG_SYNC_INTERVAL {
+            self.file.sync_data()?;
+            self.synced_at = Instant::now();
+            self.unsynced = false;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for AuditLog {
+    fn drop(&mut self) {
+        if self.unsynced {
+            let _ = self.file.sync_data();
+        }
+    }
+}
+
+impl NetnsMapper {
+    fn from_opts(opts: &Opts) -> std::io::Result<NetnsMapper> {
+        let id_map = match opts.id_map_file {
+            Some(ref x) => load_id_map(x)?,
+            None => HashMap::new(),
+        };
+
+        let force_id = std::env::var("RESOLVCONFFS_FORCE_ID").ok();
+        match force_id {
+            Some(ref id) if opts.allow_force_id => {
+                log::warn!("Mapping all requests to identifier {:?} from RESOLVCONFFS_FORCE_ID", id)
+            }
+            Some(_) => log::warn!("Ignoring RESOLVCONFFS_FORCE_ID without --allow-force-id"),
+            None if opts.allow_force_id => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "--allow-force-id requires RESOLVCONFFS_FORCE_ID environment variable",
+                ))
+            }
+            None => (),
+        }
+
+        let extensions: Vec<OsString> = opts
+            .extension()
+            .as_os_str()
+            .as_bytes()
+            .split(|x| *x == b',')
+            .map(|x| OsStr::from_bytes(x).to_owned())
+            .collect();
+        for ext in &extensions {
+            validate_extension(ext)?;
+        }
+        for x in [&opts.id_prefix, &opts.id_suffix] {
+            if x.contains(|c: char| c == '/' || c.is_control()) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Invalid --id-prefix or --id-suffix {:?}, it should not contain `/` or control characters", x),
+                ));
+            }
+        }
+
+        if opts.deny_by_default && opts.policy_file.is_none() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "--deny-by-default requires --policy-file",
+            ));
+        }
+
+        match opts.line_endings.as_str() {
+            "native" => (),
+            "crlf" if opts.read_prefix.is_some() => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "--line-endings crlf cannot be combined with --read-prefix",
+                ))
+            }
+            "crlf" => (),
+            x => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Invalid --line-endings {:?}, expected `native` or `crlf`", x),
+                ))
+            }
+        }
+
+        if opts.cow_on_write_open && opts.upper_dir.is_none() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "--cow-on-write-open requires --upper-dir",
+            ));
+        }
+        let key_source = match opts.key_source {
+            Some(ref x) => x.parse()?,
+            None => KeySource::Netns,
+        };
+        // Both compare network namespaces, which other key sources do not look at
+        if !matches!(key_source, KeySource::Netns) && (opts.init_netns_readonly || opts.host_file.is_some()) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "--init-netns-readonly and --host-file only work with --key-source netns",
+            ));
+        }
+        // Without a policy file nothing is listed, and everything would silently get the default policy
+        if opts.default_policy.is_some() && opts.policy_file.is_none() {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "--default-policy needs --policy-file"));
+        }
+
+        let default_content = match opts.default_content.as_deref() {
+            Some(_) if opts.default_file.is_some() => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "--default-file and --default-content are mutually exclusive",
+                ))
+            }
+            Some("-") => {
+                let mut content = Vec::new();
+                std::io::Read::read_to_end(&mut std::io::stdin(), &mut content)?;
+                Some(content.into())
+            }
+            Some(x) => Some(x.as_bytes().into()),
+            None => None,
+        };
+
+        let default_files_per_uid = opts
+            .default_file_for
+            .iter()
+            .map(|x| parse_uid_path(x))
+            .collect::<std::io::Result<HashMap<_, _>>>()?;
+
+        Ok(NetnsMapper {
+            backing_directory: opts.backing_directory().to_owned(),
+            extensions,
+            default_file: opts.default_file.clone(),
+            default_content,
+            default_files_per_uid,
+            max_default_copies: opts.max_default_copies,
+            default_copy_total_bytes: opts.default_copy_total_bytes,
+            own_created_files: opts.own_created_files,
+            default_copies: 0,
+            default_copied_bytes: 0,
+            populated: HashMap::new(),
+            procfs: opts.procfs().to_owned(),
+            requestor_root: opts.requestor_root,
+            upper_dir: opts.upper_dir.clone(),
+            cow_on_write_open: opts.cow_on_write_open,
+            shard: opts.shard,
+            use_tgid: opts.use_tgid,
+            readonly_netns: if opts.init_netns_readonly {
+                let link = std::fs::read_link(opts.procfs().join("1/ns/net"))?;
+                Some(link.to_string_lossy().trim().to_owned())
+            } else {
+                None
+            },
+            force_id: force_id.filter(|_| opts.allow_force_id),
+            host: match opts.host_file {
+                Some(ref x) => {
+                    let link = std::fs::read_link(opts.procfs().join("self/ns/net"))?;
+                    Some((link.to_string_lossy().trim().to_owned(), x.clone()))
+                }
+                None => None,
+            },
+            key_source,
+            id_prefix: opts.id_prefix.clone(),
+            id_suffix: opts.id_suffix.clone(),
+            key_include_starttime: opts.key_include_starttime,
+            deny_by_default: opts.deny_by_default,
+            max_id_len: opts.max_id_len,
+            policy: match opts.policy_file {
+                Some(ref x) => Some(Policy::load(x, parse_policy_word(opts.default_policy.as_deref().unwrap_or("rw"))?)?),
+                None => None,
+            },
+            id_map,
+            netns_names: opts
+                .resolve_netns_names
+                .then(|| NetnsNames::new(opts.netns_dir.clone())),
+            audit_log: match opts.audit_log {
+                Some(ref x) => Some(AuditLog::open(x)?),
+                None => None,
+            },
+            on_new_netns: opts.on_new_netns.clone().map(NewIdHook::new),
+            io_timeout: opts.io_timeout_ms.map(Duration::from_millis),
+            log_dedup: LogDedup::default(),
+        })
+    }
+
+    /// Check the setup for obvious problems, reporting them at the given log level.
+    /// Returns `false` if any problem was found.
+    fn sanity_check(&self, level: log::Level) -> bool {
+        let mut ok = true;
+        if std::fs::metadata(&self.backing_directory)
+            .map(|x| x.is_dir())
+            .ok()
+            != Some(true)
+        {
+            log::log!(
+                level,
+                "Backing directory {:?} may be not accessible",
+                self.backing_directory
+            );
+            ok = false;
+        }
+
+        if let Some(ref upper_dir) = self.upper_dir {
+            if std::fs::metadata(upper_dir).map(|x| x.is_dir()).ok() != Some(true) {
+                log::log!(level, "Upper directory {:?} may be not accessible", upper_dir);
+                ok = false;
+            }
+        }
+
+        for deffile in self.default_file.iter().chain(self.default_files_per_uid.values()) {
+            if std::fs::File::open(deffile).is_err() {
+                log::log!(level, "Default file {:?} may be unopeneable", deffile);
+                ok = false;
+            }
+            let deffile = std::fs::canonicalize(deffile).unwrap_or_else(|_| deffile.clone());
+            for dir in std::iter::once(&self.backing_directory).chain(self.upper_dir.as_ref()) {
+                if std::fs::canonicalize(dir).map_or(false, |dir| deffile.starts_with(dir)) {
+                    log::log!(level, "Default file {:?} is inside {:?}, where it may be taken for some identifier's file", deffile, dir);
+                    ok = false;
+                }
+            }
+        }
+
+        if !is_procfs(&self.procfs) {
+            // E.g. a directory of `<pid>/ns/net` symlinks for testing, where pid 1 need not exist
+            log::debug!("{:?} is not a procfs mount, not checking it further", self.procfs);
+            return ok;
+        }
+
+        let inits_netns = self.procfs.join("1/ns/net");
+        if std::fs::read_link(&inits_netns).is_err() {
+            log::log!(level, "Failed to resolve {:?}.\nYou may want to run resolvconffs as root if you want to serve multiple users.", inits_netns);
+            ok = false;
+        }
+
+        if let Some(hidepid) = procfs_hidepid(&self.procfs) {
+            if !nix::unistd::geteuid().is_root() {
+                log::log!(level, "{:?} is mounted with {}, so processes of other users are invisible and their requests fail with ENOENT.\nRun resolvconffs as root or within the group specified by procfs's gid= mount option.", self.procfs, hidepid);
+                ok = false;
+            }
+        }
+        ok
+    }
+
+    /// Procfs directory of the process to look up identifier for
+    fn key_dir(&mut self, rq: UidGidPid) -> Option<PidDir> {
+        let dir = match PidDir::open(&self.procfs, rq.pid) {
+            Ok(x) => x,
+            Err(e) => {
+                warn_ratelimited!(self.log_dedup, "Cannot open procfs directory of process {}: {}", rq.pid, e);
+                return None;
+            }
+        };
+        if !self.use_tgid {
+            return Some(dir);
+        }
+        match read_tgid(&dir) {
+            Some(tgid) if tgid != rq.pid => match PidDir::open(&self.procfs, tgid) {
+                Ok(x) => Some(x),
+                Err(_) => {
+                    log::debug!("Cannot open procfs directory of tgid {} of {}, using the pid itself", tgid, rq.pid);
+                    Some(dir)
+                }
+            },
+            Some(_) => Some(dir),
+            None => {
+                log::debug!("Cannot get tgid of {}, using the pid itself", rq.pid);
+                Some(dir)
+            }
+        }
+    }
+
+    /// Read profile name from `<dir>/<pid>` for `KeySource::PidFile`.
+    /// Files older than the process are left by an earlier process with the same pid and are ignored.
+    /// `btime` has a resolution of one second, so files written up to a second before the process started still count.
+    fn read_pidfile(&mut self, dir: &Path, piddir: &PidDir) -> Option<String> {
+        let pidfile = dir.join(format!("{}", piddir.pid));
+        let content = std::fs::read_to_string(&pidfile).ok()?;
+        let started = piddir.started();
+        let written = std::fs::metadata(&pidfile).and_then(|x| x.modified());
+        if let (Some(started), Ok(written)) = (started, written) {
+            if written + Duration::from_secs(1) < started {
+                warn_ratelimited!(self.log_dedup, "Ignoring stale {:?}, it is older than process {}", pidfile, piddir.pid);
+                return None;
+            }
+        }
+        let name = content.trim();
+        if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+            warn_ratelimited!(self.log_dedup, "Invalid profile name in {:?}", pidfile);
+            return None;
+        }
+        Some(name.to_owned())
+    }
+
+    /// Read process name for `KeySource::Comm`. Characters other than ASCII letters, digits, `.`, `-` and `_` are replaced with `_`.
+    fn read_comm(&mut self, piddir: &PidDir) -> Option<String> {
+        let path = piddir.path_of("comm");
+        let comm = match piddir.read_to_string("comm") {
+            Ok(x) => x,
+            Err(_) => {
+                warn_ratelimited!(self.log_dedup, "Failed to read {:?}", path);
+                return None;
+            }
+        };
+        let name: String = comm
+            .trim_end_matches('\n')
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_') { c } else { '_' })
+            .collect();
+        if name.is_empty() || name == "." || name == ".." {
+            warn_ratelimited!(self.log_dedup, "Unusable process name in {:?}", path);
+            return None;
+        }
+        Some(name)
+    }
+
+    /// Find out netns of the requestor: `<procfs>/<pid>/ns/net` link content and the identifier in it
+    fn read_netns(&mut self, piddir: &PidDir) -> Option<(String, String)> {
+        let netnslink = piddir.path_of("ns/net");
+        let netns = if let Ok(netns) = piddir.read_link("ns/net") {
+            netns
+        } else {
+            warn_ratelimited!(self.log_dedup, "Failed to readlink {:?}", netnslink);
+            return None;
+        };
+
+        let netns = if let Some(x) = netns.to_str() {
+            x.trim()
+        } else {
+            warn_ratelimited!(self.log_dedup, "Invalid netns symlink content in {:?}", netnslink);
+            return None;
+        };
+        // net:[4026532413]
+
+        let (net, ns) = if let Some(x) = netns.split_once(':') {
+            x
+        } else {
+            warn_ratelimited!(self.log_dedup, "netns symlink content has no `:` character in {:?}", netnslink);
+            return None;
+        };
+
+        if net != "net" {
+            warn_ratelimited!(self.log_dedup, "netns symlink content does not start with 'net:' in {:?}", netnslink);
+            return None;
+        }
+
+        let nsonly = ns.trim().trim_end_matches(']').trim_start_matches('[').trim();
+        if nsonly.is_empty() {
+            warn_ratelimited!(self.log_dedup, "netns symlink content has empty identifier in {:?}", netnslink);
+            return None;
+        }
+        Some((netns.to_owned(), nsonly.to_owned()))
+    }
+
+    /// Re-read files that may be changed while running, keeping old content if they became invalid
+    fn reload(&mut self) {
+        if let Some(ref mut policy) = self.policy {
+            match Policy::load(&policy.path, policy.default_read_only) {
+                Ok(x) => {
+                    log::info!("Reloaded {:?}", policy.path);
+                    *policy = x;
+                }
+                Err(e) => log::error!("Cannot reload {:?}, keeping previous policy: {}", policy.path, e),
+            }
+        }
+        if let Some(ref mut audit_log) = self.audit_log {
+            match audit_log.reopen() {
+                Ok(()) => log::info!("Reopened {:?}", audit_log.path),
+                Err(e) => log::error!("Cannot reopen {:?}, writing to the old file: {}", audit_log.path, e),
+            }
+        }
+        self.refresh_defaults();
+    }
+
+    /// Re-copy default files into files populated from them whose content is still as populated
+    fn refresh_defaults(&mut self) {
+        use std::os::unix::fs::MetadataExt;
+        let files: Vec<PathBuf> = self.populated.keys().cloned().collect();
+        let mut refreshed = 0;
+        for file in files {
+            let (hash, template) = match self.populated.get(&file) {
+                Some(x) if x.template.source() == Source::Default => (x.hash, x.template.clone()),
+                _ => continue,
+            };
+            let old = match std::fs::metadata(&file).and_then(|m| Ok((m, fnv1a(&std::fs::read(&file)?)))) {
+                Ok((m, current)) if current == hash => m,
+                // Changed by the user, or gone
+                _ => {
+                    self.populated.remove(&file);
+                    continue;
+                }
+            };
+            // Rewriting unchanged files would needlessly bump their mtime and wake up their watchers
+            match template.hash() {
+                Ok(x) if x == hash => continue,
+                Ok(_) => (),
+                Err(e) => {
+                    log::warn!("Cannot read {}: {}", template, e);
+                    continue;
+                }
+            }
+            if let Err(e) = template.write_to(&file) {
+                log::warn!("Cannot refresh {:?} from {}: {}", file, template, e);
+                continue;
+            }
+            if self.own_created_files {
+                let owner = (nix::unistd::Uid::from_raw(old.uid()), nix::unistd::Gid::from_raw(old.gid()));
+                if let Err(e) = nix::unistd::chown(&file, Some(owner.0), Some(owner.1)) {
+                    log::warn!("Cannot chown {:?} to {}:{}: {}", file, old.uid(), old.gid(), e);
+                }
+            }
+            match Populated::of(&file, template) {
+                Ok(x) => {
+                    self.populated.insert(file, x);
+                }
+                Err(_) => {
+                    self.populated.remove(&file);
+                }
+            }
+            refreshed += 1;
+        }
+        if refreshed > 0 {
+            log::info!("Refreshed {} unmodified files from default files", refreshed);
+        }
+    }
+
+    fn map(&mut self, rq: UidGidPid) -> Option<Mapping> {
+        if RELOAD_REQUESTED.swap(false, std::sync::atomic::Ordering::Relaxed) {
+            self.reload();
+        }
+        let key_dir = match self.force_id {
+            Some(_) if !self.key_include_starttime => None,
+            _ => Some(self.key_dir(rq)?),
+        };
+        let (netns, nsonly) = match (&self.force_id, &self.key_source) {
+            (Some(id), _) => (format!("net:[{}]", id), id.clone()),
+            (None, KeySource::Netns) => self.read_netns(key_dir.as_ref()?)?,
+            (None, KeySource::Comm) => (String::new(), self.read_comm(key_dir.as_ref()?)?),
+            (None, KeySource::PidFile(dir)) => {
+                let dir = dir.clone();
+                match self.read_pidfile(&dir, key_dir.as_ref()?) {
+                    Some(name) => (String::new(), name),
+                    None if self.deny_by_default => return None,
+                    None => {
+                        // Unannotated process gets the default file itself, so that it cannot modify it
+                        let deffile = self.default_files_per_uid.get(&rq.uid).or(self.default_file.as_ref());
+                        let mapping = deffile.map(|x| Mapping {
+                            path: x.clone(),
+                            read_only: true,
+                            source: Source::Default,
+                            content: None,
+                        });
+                        return mapping.map(|x| self.audited(rq, "", x));
+                    }
+                }
+            }
+        };
+        let mut read_only = self.readonly_netns.as_deref() == Some(netns.as_str());
+        let nsonly = nsonly.as_str();
+        let name = self.netns_names.as_mut().and_then(|x| x.lookup(nsonly));
+        let nsonly = name.as_deref().unwrap_or(nsonly);
+        let nsonly = self.id_map.get(nsonly).map(String::as_str).unwrap_or(nsonly);
+        let with_starttime;
+        let nsonly = if let (true, Some(key_dir)) = (self.key_include_starttime, key_dir.as_ref()) {
+            match read_starttime(key_dir) {
+                Some(t) => {
+                    with_starttime = format!("{}-{}", nsonly, t);
+                    with_starttime.as_str()
+                }
+                None => {
+                    warn_ratelimited!(self.log_dedup, "Cannot read start time of process {}", key_dir.pid);
+                    return None;
+                }
+            }
+        } else {
+            nsonly
+        };
+        let audit_id = self.audit_log.is_some().then(|| nsonly.to_owned());
+        if nsonly.len() > self.max_id_len {
+            warn_ratelimited!(self.log_dedup, "Identifier is {} bytes long, more than --max-id-len", nsonly.len());
+            return None;
+        }
+        if let Some(ref policy) = self.policy {
+            if self.deny_by_default && !policy.lists(nsonly, rq.uid) {
+                log::debug!("Identifier {:?} and uid {} are not in --policy-file, denying", nsonly, rq.uid);
+                return None;
+            }
+            read_only |= policy.read_only(nsonly, rq.uid);
+        }
+
+        // Limits and policy above apply to the host netns too
+        let host_mapping = match self.host {
+            Some((ref host_netns, ref host_file)) if *host_netns == netns => Some(Mapping {
+                path: host_file.clone(),
+                read_only,
+                source: Source::Own,
+                content: None,
+            }),
+            _ => None,
+        };
+        if let Some(mapping) = host_mapping {
+            return Some(self.audited(rq, audit_id.as_deref().unwrap_or(""), mapping));
+        }
+
+        if let Some(ref mut hook) = self.on_new_netns {
+            hook.notify(nsonly);
+        }
+
+        let root = if self.requestor_root {
+            let mut root = PathBuf::with_capacity(self.procfs.as_os_str().len() + 16);
+            root.push(&self.procfs);
+            root.push(format!("{}", rq.pid));
+            root.push("root");
+            if std::fs::metadata(&root).is_err() {
+                warn_ratelimited!(self.log_dedup, "Cannot access mount namespace root {:?}", root);
+                return None;
+            }
+            Some(root)
+        } else {
+            None
+        };
+        let shard = self.shard.then(|| shard_name(nsonly));
+        let in_root = |dir: &Path| {
+            let mut dir = match root {
+                Some(ref root) => root.join(dir.strip_prefix("/").unwrap_or(dir)),
+                None => dir.to_owned(),
+            };
+            dir.extend(&shard);
+            dir
+        };
+        let file_id = format!("{}{}{}", self.id_prefix, nsonly, self.id_suffix);
+        let timeout = self.io_timeout;
+        let (mut targetfile, ext) = self.timely(find_target_path(&in_root(&self.backing_directory), &file_id, &self.extensions, 0, timeout))?;
+
+        if let Some(upper_dir) = self.upper_dir.clone() {
+            // Copied up file keeps extension of the lower one
+            let (upperfile, _) = self.timely(find_target_path(&in_root(&upper_dir), &file_id, &self.extensions, ext, timeout))?;
+            let lower_exists = self.timely(exists_within(timeout, &targetfile))?;
+            if self.timely(exists_within(timeout, &upperfile))? || !lower_exists {
+                targetfile = upperfile;
+            } else if rq.write && !read_only && !(self.cow_on_write_open && is_writable(&targetfile)) {
+                let (shard, lower, upper) = (self.shard, targetfile.clone(), upperfile.clone());
+                let copy_up = move || create_shard_dir(shard, &upper).and_then(|()| copy_atomically(&lower, &upper));
+                match io_with_deadline(timeout, copy_up) {
+                    Ok(_) => targetfile = upperfile,
+                    Err(e) => {
+                        // Serve the lower file read-only, so that writers get EROFS rather than ENOENT
+                        warn_ratelimited!(self.log_dedup, "Cannot copy up {:?} to {:?}: {}", targetfile, upperfile, e);
+                        read_only = true;
+                    }
+                }
+            }
+        }
+
+        let template = match self.default_files_per_uid.get(&rq.uid).or(self.default_file.as_ref()) {
+            Some(x) => Some(Template::File(x.clone())),
+            None => self.default_content.clone().map(Template::Content),
+        };
+        if let Some(Template::File(ref deffile)) = template {
+            let (a, b) = (targetfile.clone(), deffile.clone());
+            if self.timely(with_deadline(timeout, move || Ok(is_same_file(&a, &b)), drop))? {
+                // Misconfiguration made the default file look like a per-identifier file; never let it be modified
+                warn_ratelimited!(self.log_dedup, "{:?} is the default file itself, serving it read-only", targetfile);
+                let mapping = Mapping {
+                    path: targetfile,
+                    read_only: true,
+                    source: Source::Default,
+                    content: None,
+                };
+                return Some(self.audited(rq, audit_id.as_deref().unwrap_or(""), mapping));
+            }
+        }
+        if let Some(template) = template {
+            if !self.timely(exists_within(timeout, &targetfile))? && !self.copy_default(&template, &targetfile, rq) {
+                if let Template::File(deffile) = template {
+                    // Serve the default file itself, so that at least reading works
+                    let mapping = Mapping {
+                        path: deffile,
+                        read_only: true,
+                        source: Source::Default,
+                        content: None,
+                    };
+                    return Some(self.audited(rq, audit_id.as_deref().unwrap_or(""), mapping));
+                }
+            }
+        }
+
+        let source = self.source_of(&targetfile);
+        let mapping = Mapping {
+            path: targetfile,
+            read_only,
+            source,
+            content: None,
+        };
+        Some(self.audited(rq, audit_id.as_deref().unwrap_or(""), mapping))
+    }
+
+    /// Record `mapping` of `rq` in `--audit-log`, passing it through
+    fn audited(&mut self, rq: UidGidPid, id: &str, mapping: Mapping) -> Mapping {
+        if let Some(ref mut audit_log) = self.audit_log {
+            if let Err(e) = audit_log.record(rq, id, &mapping.path) {
+                warn_ratelimited!(self.log_dedup, "Cannot write to audit log {:?}: {}", audit_log.path, e);
+            }
+        }
+        mapping
+    }
+
+    /// Pass `result` of a backing file check through, logging why mapping gives up if it failed
+    fn timely<T>(&mut self, result: nix::Result<T>) -> Option<T> {
+        if let Err(e) = result {
+            warn_ratelimited!(self.log_dedup, "Cannot check backing files: {}", e);
+        }
+        result.ok()
+    }
+
+    /// Whether `file` is still as populated by `copy_default`
+    fn source_of(&mut self, file: &Path) -> Source {
+        use std::os::unix::fs::MetadataExt;
+        let (ino, mtime, mtime_nsec, hash, source) = match self.populated.get(file) {
+            Some(x) => (x.ino, x.mtime, x.mtime_nsec, x.hash, x.template.source()),
+            None => return Source::Own,
+        };
+        let path = file.to_owned();
+        match io_with_deadline(self.io_timeout, move || std::fs::metadata(&path)) {
+            Ok(m) if (m.ino(), m.mtime(), m.mtime_nsec()) == (ino, mtime, mtime_nsec) => return source,
+            // Unknown for now, it may still be as populated
+            Err(nix::errno::Errno::ETIMEDOUT) | Err(nix::errno::Errno::EIO) => return Source::Own,
+            Ok(_) => (),
+            Err(_) => {
+                self.populated.remove(file);
+                return Source::Own;
+            }
+        }
+        // Touched, e.g. rewritten with the same content by an editor
+        let path = file.to_owned();
+        match io_with_deadline(self.io_timeout, move || Ok((std::fs::metadata(&path)?, std::fs::read(&path)?))) {
+            Ok((m, content)) if fnv1a(&content) == hash => {
+                if let Some(x) = self.populated.get_mut(file) {
+                    x.ino = m.ino();
+                    x.mtime = m.mtime();
+                    x.mtime_nsec = m.mtime_nsec();
+                }
+                source
+            }
+            Err(nix::errno::Errno::ETIMEDOUT) | Err(nix::errno::Errno::EIO) => Source::Own,
+            _ => {
+                self.populated.remove(file);
+                Source::Own
+            }
+        }
+    }
+
+    /// Populate missing `target` from `template`, unless copy quotas are exhausted. Returns whether `target` got populated.
+    fn copy_default(&mut self, template: &Template, target: &Path, rq: UidGidPid) -> bool {
+        if let Some(max) = self.max_default_copies {
+            if self.default_copies >= max {
+                warn_ratelimited!(self.log_dedup, "Not copying default file to {:?}: --max-default-copies reached", target);
+                return false;
+            }
+        }
+        if let Some(max) = self.default_copy_total_bytes {
+            if self.default_copied_bytes + template.len() > max {
+                warn_ratelimited!(self.log_dedup, "Not copying default file to {:?}: --default-copy-total-bytes reached", target);
+                return false;
+            }
+        }
+        let (shard, source, path) = (self.shard, template.clone(), target.to_owned());
+        let copy = move || {
+            let len = create_shard_dir(shard, &path).and_then(|()| source.write_to(&path))?;
+            Ok((len, Populated::of(&path, source).ok()))
+        };
+        match io_with_deadline(self.io_timeout, copy) {
+            Ok((len, populated)) => {
+                self.default_copies += 1;
+                self.default_copied_bytes += len;
+                match populated {
+                    Some(_) if self.populated.len() >= POPULATED_MAX_ENTRIES => {
+                        warn_ratelimited!(self.log_dedup, "Not tracking {:?}: {} populated files tracked already", target, POPULATED_MAX_ENTRIES);
+                    }
+                    Some(x) => {
+                        self.populated.insert(target.to_owned(), x);
+                    }
+                    None => (),
+                }
+                if self.own_created_files {
+                    let owner = (nix::unistd::Uid::from_raw(rq.uid), nix::unistd::Gid::from_raw(rq.gid));
+                    if let Err(e) = nix::unistd::chown(target, Some(owner.0), Some(owner.1)) {
+                        warn_ratelimited!(self.log_dedup, "Cannot chown {:?} to {}:{}: {}", target, rq.uid, rq.gid, e);
+                    }
+                }
+                true
+            }
+            Err(_) => {
+                warn_ratelimited!(self.log_dedup, "Cannot copy from {} to {:?}", template, target);
+                false
+            }
+        }
+    }
+
+    /// Stat backing files of network namespaces of running processes, so that first real requests find them in kernel caches.
+    /// Unlike `map`, this runs no hooks, copies no default files and writes no audit records. Returns the number of files found.
+    fn prewarm(&mut self) -> usize {
+        if !matches!(self.key_source, KeySource::Netns) || self.force_id.is_some() || self.key_include_starttime || self.requestor_root {
+            log::info!("Not prewarming, backing files do not depend on the network namespace alone");
+            return 0;
+        }
+        let entries = match std::fs::read_dir(&self.procfs) {
+            Ok(x) => x,
+            Err(e) => {
+                log::warn!("Cannot prewarm from {:?}: {}", self.procfs, e);
+                return 0;
+            }
+        };
+        let mut seen = std::collections::HashSet::new();
+        let mut found = 0;
+        for entry in entries.flatten() {
+            if seen.len() >= PREWARM_MAX_NETNS {
+                log::info!("Stopping prewarm after {} network namespaces", PREWARM_MAX_NETNS);
+                break;
+            }
+            let piddir = match entry.file_name().to_str().and_then(|x| x.parse().ok()) {
+                Some(pid) => match PidDir::open(&self.procfs, pid) {
+                    Ok(x) => x,
+                    Err(_) => continue,
+                },
+                None => continue,
+            };
+            let (netns, nsonly) = match self.read_netns(&piddir) {
+                Some(x) => x,
+                None => continue,
+            };
+            if !seen.insert(netns.clone()) || matches!(self.host, Some((ref host_netns, _)) if *host_netns == netns) {
+                continue;
+            }
+            let name = self.netns_names.as_mut().and_then(|x| x.lookup(&nsonly));
+            let nsonly = name.as_deref().unwrap_or(&nsonly);
+            let nsonly = self.id_map.get(nsonly).map(String::as_str).unwrap_or(nsonly);
+            if nsonly.len() > self.max_id_len {
+                continue;
+            }
+            let file_id = format!("{}{}{}", self.id_prefix, nsonly, self.id_suffix);
+            let shard = self.shard.then(|| shard_name(nsonly));
+            for dir in std::iter::once(&self.backing_directory).chain(&self.upper_dir) {
+                let mut dir = dir.clone();
+                dir.extend(&shard);
+                let exists = find_target_path(&dir, &file_id, &self.extensions, 0, self.io_timeout)
+                    .and_then(|(path, _)| exists_within(self.io_timeout, &path));
+                if let Ok(true) = exists {
+                    found += 1;
+                    break;
+                }
+            }
+        }
+        log::info!("Prewarmed {} backing files of {} network namespaces", found, seen.len());
+        found
+    }
+}
+
+/// Upper bound of network namespaces looked at by `NetnsMapper::prewarm`
+const PREWARM_MAX_NETNS: usize = 1024;
+
+/// Mount `fs` and serve it from a background thread, like `fuser::mount2`, but without blocking.
+/// The filesystem is unmounted when the returned session is dropped or joined.
+/// To wait for an external unmount instead, join the session's `guard` thread.
+fn spawn_mount2<F: Mapper + 'static>(
+    fs: FileMapperFs<F>,
+    mountpoint: impl AsRef<Path>,
+    options: &[fuser::MountOption],
+) -> std::io::Result<fuser::BackgroundSession> {
+    fuser::Session::new(fs, mountpoint.as_ref(), options)?.spawn()
+}
+
+/// `--mount-via` bind mount over the mountpoint, detached when dropped
+struct BindMount(PathBuf);
+
+impl Drop for BindMount {
+    fn drop(&mut self) {
+        match nix::mount::umount2(&self.0, nix::mount::MntFlags::MNT_DETACH) {
+            // Already unmounted from outside
+            Ok(()) | Err(nix::errno::Errno::EINVAL) => (),
+            Err(e) => log::warn!("Cannot unmount bind mount at {:?}: {}", self.0, e),
+        }
+    }
+}
+
+/// Connect to systemd's `NOTIFY_SOCKET`, if it is set.
+/// Connected socket keeps working after chroot, unlike the path.
+fn connect_notify_socket() -> Option<std::os::unix::net::UnixDatagram> {
+    use nix::sys::socket::{AddressFamily, SockAddr, SockFlag, SockType, UnixAddr};
+    use std::os::unix::io::FromRawFd;
+    let path = std::env::var_os("NOTIFY_SOCKET")?;
+    let addr = match path.as_bytes().strip_prefix(b"@") {
+        Some(name) => UnixAddr::new_abstract(name),
+        None => UnixAddr::new(path.as_os_str()),
+    };
+    let result = addr.and_then(|addr| {
+        let fd = nix::sys::socket::socket(AddressFamily::Unix, SockType::Datagram, SockFlag::SOCK_CLOEXEC, None)?;
+        // Owning the descriptor right away closes it if connecting fails
+        let sock = unsafe { std::os::unix::net::UnixDatagram::from_raw_fd(fd) };
+        nix::sys::socket::connect(fd, &SockAddr::Unix(addr)).map(|()| sock)
+    });
+    match result {
+        Ok(x) => Some(x),
+        Err(e) => {
+            log::warn!("Cannot connect to NOTIFY_SOCKET {:?}: {}", path, e);
+            None
+        }
+    }
+}
+
+/// Send `sd_notify`-style state like `READY=1` to systemd
+fn sd_notify(sock: &Option<std::os::unix::net::UnixDatagram>, state: &str) {
+    if let Some(sock) = sock {
+        if let Err(e) = sock.send(state.as_bytes()) {
+            log::warn!("Cannot notify systemd about {}: {}", state, e);
+        }
+    }
+}
+
+/// Whether `path` (or its parent, if `path` does not exist) is on a read-only filesystem
+fn on_read_only_fs(path: &Path) -> bool {
+    use nix::sys::statvfs::{statvfs, FsFlags};
+    let probe = if path.exists() { path } else { path.parent().unwrap_or(Path::new("/")) };
+    statvfs(probe).map(|x| x.flags().contains(FsFlags::ST_RDONLY)).unwrap_or(false)
+}
+
+/// Create missing shard subdirectory for `file`, if `shard`ing
+fn create_shard_dir(shard: bool, file: &Path) -> std::io::Result<()> {
+    match file.parent() {
+        Some(dir) if shard => std::fs::create_dir_all(dir),
+        _ => Ok(()),
+    }
+}
+
+/// Whether `a` and `b` exist and are the same file, i.e. have the same device and inode numbers
+fn is_same_file(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (std::fs::metadata(a), std::fs::metadata(b)) {
+        (Ok(a), Ok(b)) => (a.dev(), a.ino()) == (b.dev(), b.ino()),
+        _ => false,
+    }
+}
+
+/// First of `paths` that is `mountpoint` itself, the same file under another name, or something inside it
+fn depends_on_mountpoint<'a>(mountpoint: &Path, paths: &[&'a Path]) -> Option<&'a Path> {
+    let mountpoint_canonical = std::fs::canonicalize(mountpoint).ok()?;
+    paths.iter().copied().find(|path| {
+        is_same_file(path, mountpoint) || std::fs::canonicalize(path).map_or(false, |x| x.starts_with(&mountpoint_canonical))
+    })
+}
+
+/// Parse `--cpu-affinity` list like `0-1,4`
+fn parse_cpu_list(x: &str) -> std::io::Result<nix::sched::CpuSet> {
+    let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Invalid CPU list {:?}", x));
+    let mut set = nix::sched::CpuSet::new();
+    for range in x.split(',') {
+        let (first, last) = range.split_once('-').unwrap_or((range, range));
+        let first: usize = first.trim().parse().map_err(|_| invalid())?;
+        let last: usize = last.trim().parse().map_err(|_| invalid())?;
+        if first > last {
+            return Err(invalid());
+        }
+        for cpu in first..=last {
+            set.set(cpu).map_err(|_| invalid())?;
+        }
+    }
+    Ok(set)
+}
+
+/// Apply `--cpu-affinity`, `--nice` and `--rt-priority` to all threads of resolvconffs, found in `<procfs>/self/task`.
+/// Threads started later inherit the settings.
+fn apply_scheduling(opts: &Opts, cpus: Option<&nix::sched::CpuSet>) -> std::io::Result<()> {
+    if cpus.is_none() && opts.nice.is_none() && opts.rt_priority.is_none() {
+        return Ok(());
+    }
+    for entry in std::fs::read_dir(opts.procfs().join("self/task"))? {
+        let tid: libc::pid_t = match entry?.file_name().to_str().and_then(|x| x.parse().ok()) {
+            Some(x) => x,
+            None => continue,
+        };
+        if let Some(cpus) = cpus {
+            nix::sched::sched_setaffinity(nix::unistd::Pid::from_raw(tid), cpus)?;
+        }
+        if let Some(nice) = opts.nice {
+            if unsafe { libc::setpriority(libc::PRIO_PROCESS as _, tid as libc::id_t, nice) } == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        if let Some(priority) = opts.rt_priority {
+            let param = libc::sched_param { sched_priority: priority };
+            if unsafe { libc::sched_setscheduler(tid, libc::SCHED_FIFO, &param) } == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// `NS_GET_NSTYPE` ioctl from `linux/nsfs.h`
+const NS_GET_NSTYPE: libc::c_ulong = 0xb703;
+
+/// Move resolvconffs into the namespace of nsfs file `path` for `--setns`.
+/// Must be called while resolvconffs is single-threaded, as the kernel refuses to move multi-threaded processes to other mount namespaces.
+fn join_namespace(path: &Path) -> std::io::Result<()> {
+    use nix::sched::CloneFlags;
+    let fd = nix::fcntl::open(path, OFlag::O_RDONLY | OFlag::O_CLOEXEC, nix::sys::stat::Mode::empty())?;
+    let result = match unsafe { libc::ioctl(fd, NS_GET_NSTYPE as _) } {
+        -1 => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("--setns {:?} is not a namespace file: {}", path, std::io::Error::last_os_error()),
+        )),
+        x if x == libc::CLONE_NEWNET || x == libc::CLONE_NEWNS => {
+            nix::sched::setns(fd, CloneFlags::from_bits_truncate(x)).map_err(std::io::Error::from)
+        }
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("--setns {:?}: only network and mount namespaces are supported", path),
+        )),
+    };
+    let _ = nix::unistd::close(fd);
+    result
+}
+
+/// Paths resolvconffs keeps using after startup, which must be reachable inside `--chroot`.
+/// Files read only at startup (`--id-map-file`, `--read-prefix`) are not needed there.
+fn chroot_required(opts: &Opts) -> std::io::Result<Vec<PathBuf>> {
+    let mut required = vec![opts.backing_directory().to_owned(), opts.procfs().to_owned()];
+    required.extend(opts.upper_dir.clone());
+    required.extend(opts.default_file.clone());
+    for x in &opts.default_file_for {
+        required.push(parse_uid_path(x)?.1);
+    }
+    required.extend(opts.mirror_dir.clone());
+    required.extend(opts.policy_file.clone());
+    required.extend(opts.host_file.clone());
+    required.extend(opts.on_new_netns.clone());
+    if opts.resolve_netns_names {
+        required.push(opts.netns_dir.clone());
+    }
+    if let Some(dir) = opts.key_source.as_deref().and_then(|x| x.strip_prefix("pidfile:")) {
+        required.push(dir.into());
+    }
+    // The log is reopened by path on SIGHUP, possibly after being rotated away
+    if let Some(dir) = opts.audit_log.as_deref().and_then(Path::parent) {
+        required.push(dir.to_owned());
+    }
+    Ok(required)
+}
+
+/// Confine resolvconffs to `dir`, checking beforehand that `required` paths would be reachable there
+fn enter_chroot(dir: &Path, required: &[&Path]) -> std::io::Result<()> {
+    for path in required {
+        let inside = dir.join(path.strip_prefix("/").unwrap_or(path));
+        if std::fs::metadata(&inside).is_err() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("{:?} is not available as {:?}, bind-mount it there", path, inside),
+            ));
+        }
+    }
+    nix::unistd::chroot(dir)?;
+    nix::unistd::chdir("/")?;
+    Ok(())
+}
+
+/// Parse `<uid>:<path>` command line argument
+fn parse_uid_path(x: &str) -> std::io::Result<(u32, PathBuf)> {
+    match x.split_once(':').map(|(uid, path)| (uid.parse(), path)) {
+        Some((Ok(uid), path)) if !path.is_empty() => Ok((uid, PathBuf::from(path))),
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Invalid {:?}, expected <uid>:<path>", x),
+        )),
+    }
+}
+
+/// Parse `<uid>:<gid>` command line argument
+fn parse_owner(x: &str) -> std::io::Result<(u32, u32)> {
+    match x.split_once(':').map(|(uid, gid)| (uid.parse(), gid.parse())) {
+        Some((Ok(uid), Ok(gid))) => Ok((uid, gid)),
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Invalid {:?}, expected <uid>:<gid>", x),
+        )),
+    }
+}
+
+/// Translate a `-o` option to typed `MountOption` where `fuser` has a variant for it, falling back to `CUSTOM`.
+fn parse_fuse_opt(x: &str) -> fuser::MountOption {
+    use fuser::MountOption;
+    match x {
+        "auto_unmount" => MountOption::AutoUnmount,
+        "allow_other" => MountOption::AllowOther,
+        "allow_root" => MountOption::AllowRoot,
+        "default_permissions" => MountOption::DefaultPermissions,
+        "dev" => MountOption::Dev,
+        "nodev" => MountOption::NoDev,
+        "suid" => MountOption::Suid,
+        "nosuid" => MountOption::NoSuid,
+        "ro" => MountOption::RO,
+        "rw" => MountOption::RW,
+        "exec" => MountOption::Exec,
+        "noexec" => MountOption::NoExec,
+        "atime" => MountOption::Atime,
+        "noatime" => MountOption::NoAtime,
+        "dirsync" => MountOption::DirSync,
+        "sync" => MountOption::Sync,
+        "async" => MountOption::Async,
+        x if x.starts_with("fsname=") => MountOption::FSName(x["fsname=".len()..].to_owned()),
+        x if x.starts_with("subtype=") => MountOption::Subtype(x["subtype=".len()..].to_owned()),
+        x => MountOption::CUSTOM(x.to_owned()),
+    }
+}
+
+/// `FileMapperFs` tunables for `opts`
+fn fs_options(opts: &Opts) -> std::io::Result<FsOptions> {
+    Ok(FsOptions {
+        virtual_etag: opts.virtual_etag,
+        stale_ok: opts.stale_ok,
+        stale_max_bytes: opts.stale_max_bytes,
+        report_owner: opts.report_owner.as_deref().map(parse_owner).transpose()?,
+        page_cache: opts.page_cache,
+        attr_ttl: Duration::from_millis(opts.attr_timeout_ms),
+        read_prefix: match opts.read_prefix {
+            Some(ref x) => Some(std::fs::read(x)?.into()),
+            None => None,
+        },
+        io_accounting: opts.io_accounting,
+        permission_checks: opts.no_default_permissions_for_root,
+        procfs: Some(opts.procfs().to_owned()),
+        max_open_handles: opts.max_open_handles,
+        noatime: opts.noatime,
+        mirror_dir: opts.mirror_dir.clone(),
+        io_timeout: opts.io_timeout_ms.map(Duration::from_millis),
+        hash_ino: opts.hash_ino,
+        follow_symlinks: opts.follow_backing_symlinks,
+        log_requests: opts.log_requests,
+        debug_xattrs: opts.expose_debug_xattrs,
+        coalesce_writes: opts.coalesce_writes,
+        crlf: opts.line_endings == "crlf",
+        max_file_size: opts.max_file_size,
+        emergency_content: opts
+            .emergency_fallback
+            .then(|| EMERGENCY_RESOLV_CONF.replace("{}", &opts.emergency_nameserver).into_bytes().into()),
+        allowed_ops: match opts.allowed_ops {
+            Some(ref x) => OpSet::parse(x)?,
+            None => OpSet::default(),
+        },
+    })
+}
+
+/// Mount options for `opts`. Other users are allowed in unless `-o allow_root` restricts access to root, as fuser refuses to combine the two.
+fn fuse_mount_options(opts: &Opts) -> Vec<fuser::MountOption> {
+    use fuser::MountOption;
+    let mut fuse_opts = Vec::<MountOption>::with_capacity(3 + opts.other_fuse_opts.len());
+    fuse_opts.push(MountOption::FSName("resolvconffs".to_owned()));
+    fuse_opts.push(MountOption::DefaultPermissions);
+    for x in &opts.other_fuse_opts {
+        fuse_opts.extend(x.split(',').filter(|x| !x.is_empty()).map(parse_fuse_opt));
+    }
+    if !fuse_opts.contains(&MountOption::AllowRoot) && !fuse_opts.contains(&MountOption::AllowOther) {
+        fuse_opts.push(MountOption::AllowOther);
+    }
+    fuse_opts
+}
+
+const SELFTEST_CONTENT: &str = "# resolvconffs selftest\nnameserver 127.0.0.1\n";
+
+/// `FUSE_*` init flags reported by `probe` command.
+/// Values are spelled out, as `fuser::consts` only has those enabled by `abi-7-*` features.
+const PROBED_CAPABILITIES: [(&str, u32); 26] = [
+    ("async_read", 1 << 0),
+    ("posix_locks", 1 << 1),
+    ("file_ops", 1 << 2),
+    ("atomic_o_trunc", 1 << 3),
+    ("export_support", 1 << 4),
+    ("big_writes", 1 << 5),
+    ("dont_mask", 1 << 6),
+    ("splice_write", 1 << 7),
+    ("splice_move", 1 << 8),
+    ("splice_read", 1 << 9),
+    ("flock_locks", 1 << 10),
+    ("has_ioctl_dir", 1 << 11),
+    ("auto_inval_data", 1 << 12),
+    ("do_readdirplus", 1 << 13),
+    ("readdirplus_auto", 1 << 14),
+    ("async_dio", 1 << 15),
+    ("writeback_cache", 1 << 16),
+    ("no_open_support", 1 << 17),
+    ("parallel_dirops", 1 << 18),
+    ("handle_killpriv", 1 << 19),
+    ("posix_acl", 1 << 20),
+    ("abort_error", 1 << 21),
+    ("max_pages", 1 << 22),
+    ("cache_symlinks", 1 << 23),
+    ("no_opendir_support", 1 << 24),
+    ("explicit_inval_data", 1 << 25),
+];
+
+/// Filesystem that only reports kernel's capabilities from `init` and serves nothing
+struct ProbeFs {
+    result: std::sync::mpsc::Sender<Vec<(&'static str, bool)>>,
+}
+
+impl fuser::Filesystem for ProbeFs {
+    fn init(&mut self, _req: &fuser::Request<'_>, config: &mut fuser::KernelConfig) -> Result<(), libc::c_int> {
+        let caps = PROBED_CAPABILITIES
+            .iter()
+            .map(|&(name, bit)| (name, config.add_capabilities(bit).is_ok()))
+            .collect();
+        let _ = self.result.send(caps);
+        Ok(())
+    }
+}
+
+/// `probe` command: mount a throwaway filesystem and print which FUSE capabilities the kernel offers
+fn probe() -> std::io::Result<()> {
+    let mountpoint = std::env::temp_dir().join(format!("resolvconffs-probe-{}", std::process::id()));
+    std::fs::write(&mountpoint, b"")?;
+    let (tx, rx) = std::sync::mpsc::channel();
+    let result = fuser::Session::new(ProbeFs { result: tx }, &mountpoint, &[fuser::MountOption::FSName("resolvconffs-probe".to_owned())])
+        .and_then(|x| x.spawn())
+        .and_then(|session| {
+            // Kernel sends init right after mounting
+            let caps = rx.recv_timeout(Duration::from_secs(5));
+            drop(session);
+            caps.map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "no FUSE init request from kernel"))
+        });
+    let _ = std::fs::remove_file(&mountpoint);
+    for (name, supported) in result? {
+        println!("{:<20} {}", name, if supported { "yes" } else { "no" });
+    }
+    Ok(())
+}
+
+/// Environment variables consulted by `Opts::merge_env`
+const ENV_VARS: [&str; 6] = [
+    "RESOLVCONFFS_BACKING_DIR",
+    "RESOLVCONFFS_MOUNTPOINT",
+    "RESOLVCONFFS_EXTENSION",
+    "RESOLVCONFFS_DEFAULT_FILE",
+    "RESOLVCONFFS_PROCFS",
+    "RESOLVCONFFS_FUSE_OPTS",
+];
+
+/// Quote a word for systemd's command line and `Environment=` syntax, also escaping specifier and variable expansion
+fn systemd_quote(word: &str) -> String {
+    let escaped = word
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('%', "%%")
+        .replace('$', "$$");
+    if !escaped.is_empty() && escaped == word && !word.contains(|c: char| c.is_whitespace() || c == '\'' || c == ';') {
+        escaped
+    } else {
+        format!("\"{}\"", escaped)
+    }
+}
+
+/// Minimal JSON serialization for `--print-config`
+trait ToJson {
+    fn to_json(&self, out: &mut String);
+}
+
+impl ToJson for bool {
+    fn to_json(&self, out: &mut String) {
+        out.push_str(if *self { "true" } else { "false" });
+    }
+}
+
+impl ToJson for u64 {
+    fn to_json(&self, out: &mut String) {
+        out.push_str(&self.to_string());
+    }
+}
+
+impl ToJson for i32 {
+    fn to_json(&self, out: &mut String) {
+        out.push_str(&self.to_string());
+    }
+}
+
+impl ToJson for usize {
+    fn to_json(&self, out: &mut String) {
+        out.push_str(&self.to_string());
+    }
+}
+
+impl ToJson for String {
+    fn to_json(&self, out: &mut String) {
+        json_string(self, out);
+    }
+}
+
+/// Non-UTF-8 paths are output lossily
+impl ToJson for Path {
+    fn to_json(&self, out: &mut String) {
+        json_string(&self.to_string_lossy(), out);
+    }
+}
+
+impl ToJson for PathBuf {
+    fn to_json(&self, out: &mut String) {
+        self.as_path().to_json(out);
+    }
+}
+
+impl<T: ToJson + ?Sized> ToJson for &T {
+    fn to_json(&self, out: &mut String) {
+        (**self).to_json(out);
+    }
+}
+
+impl<T: ToJson> ToJson for Option<T> {
+    fn to_json(&self, out: &mut String) {
+        match self {
+            Some(x) => x.to_json(out),
+            None => out.push_str("null"),
+        }
+    }
+}
+
+impl<T: ToJson> ToJson for Vec<T> {
+    fn to_json(&self, out: &mut String) {
+        out.push('[');
+        for (i, x) in self.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            x.to_json(out);
+        }
+        out.push(']');
+    }
+}
+
+fn json_string(x: &str, out: &mut String) {
+    out.push('"');
+    for c in x.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// `print-unit` command: write systemd service unit that starts resolvconffs with given arguments to stdout
+fn print_unit(args: Vec<String>) -> std::io::Result<()> {
+    let mut opts = Opts::parse_args(&args, gumdrop::ParsingStyle::AllOptions)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string()))?;
+    opts.merge_env()?;
+
+    let exe = std::env::current_exe()?;
+    let mut exec_start = systemd_quote(&exe.to_string_lossy());
+    for arg in &args {
+        exec_start.push(' ');
+        exec_start.push_str(&systemd_quote(arg));
+    }
+
+    let mountpoint_dir = opts.mountpoint_file().parent().unwrap_or(Path::new("/"));
+    let mut mounts = vec![opts.backing_directory(), mountpoint_dir, opts.procfs()];
+    mounts.extend(opts.upper_dir.as_deref());
+    let mounts: Vec<String> = mounts.iter().map(|x| systemd_quote(&x.to_string_lossy())).collect();
+
+    println!("[Unit]");
+    println!("Description=Per network namespace {}", opts.mountpoint_file().display());
+    println!("After=local-fs.target");
+    println!("Before=nss-lookup.target network-pre.target");
+    println!("Wants=nss-lookup.target");
+    println!("RequiresMountsFor={}", mounts.join(" "));
+    println!();
+    println!("[Service]");
+    println!("Type=notify");
+    for name in ENV_VARS {
+        if let Ok(value) = std::env::var(name) {
+            println!("Environment={}", systemd_quote(&format!("{}={}", name, value)));
+        }
+    }
+    println!("ExecStart={}", exec_start);
+    println!("Restart=on-failure");
+    println!();
+    println!("[Install]");
+    println!("WantedBy=multi-user.target");
+    Ok(())
+}
+
+/// `resolvconffs selftest`: run the mapping pipeline for own process against a temporary backing directory, without mounting anything
+fn selftest() -> std::io::Result<()> {
+    let dir = std::env::temp_dir().join(format!("resolvconffs-selftest-{}", std::process::id()));
+    std::fs::create_dir(&dir)?;
+    let result = selftest_in(&dir);
+    let _ = std::fs::remove_dir_all(&dir);
+    result
+}
+
+fn selftest_in(dir: &Path) -> std::io::Result<()> {
+    let fail = |msg: String| Err(std::io::Error::new(std::io::ErrorKind::Other, format!("selftest failed: {}", msg)));
+
+    let backing_directory = dir.join("backing");
+    let default_file = dir.join("default.conf");
+    std::fs::create_dir(&backing_directory)?;
+    std::fs::write(&default_file, SELFTEST_CONTENT)?;
+
+    let args = [
+        backing_directory.to_string_lossy().into_owned(),
+        dir.join("mountpoint").to_string_lossy().into_owned(),
+        "--default-file".to_owned(),
+        default_file.to_string_lossy().into_owned(),
+    ];
+    let mut opts = Opts::parse_args_default(&args).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+    opts.merge_env()?;
+    let mut mapper = NetnsMapper::from_opts(&opts)?;
+    if !mapper.sanity_check(log::Level::Warn) {
+        println!("selftest: sanity check reported problems, see above");
+    }
+
+    let pid = std::process::id();
+    let netns = std::fs::read_link(opts.procfs().join(format!("{}/ns/net", pid)))?;
+    let rq = UidGidPid {
+        uid: nix::unistd::getuid().as_raw(),
+        gid: nix::unistd::getgid().as_raw(),
+        pid,
+        write: false,
+    };
+    let target = match mapper.map(rq) {
+        Some(x) => x.path,
+        None => return fail(format!("no mapping for own process ({:?})", netns)),
+    };
+    let netns = netns.to_string_lossy();
+    let id = netns.trim().trim_start_matches("net:[").trim_end_matches(']');
+    let expected = backing_directory.join(format!("{}.conf", id));
+    if target != expected {
+        return fail(format!("mapped to {:?} instead of {:?}", target, expected));
+    }
+    match std::fs::read_to_string(&target) {
+        Ok(x) if x == SELFTEST_CONTENT => (),
+        Ok(_) => return fail(format!("{:?} content differs from default file", target)),
+        Err(e) => return fail(format!("default file was not copied to {:?}: {}", target, e)),
+    }
+    println!("selftest OK: pid {} in {} maps to {:?}, populated from default file", pid, netns, target);
+
+    // The same with a directory standing in for procfs, as used for testing without real network namespaces
+    let fixture = dir.join("proc");
+    std::fs::create_dir_all(fixture.join(format!("{}/ns", pid)))?;
+    std::os::unix::fs::symlink("net:[1234567]", fixture.join(format!("{}/ns/net", pid)))?;
+    opts.procfs = Some(fixture.clone());
+    let mut mapper = NetnsMapper::from_opts(&opts)?;
+    if !mapper.sanity_check(log::Level::Warn) {
+        println!("selftest: sanity check reported problems with fixture procfs, see above");
+    }
+    let expected = backing_directory.join("1234567.conf");
+    match mapper.map(rq) {
+        Some(x) if x.path == expected => (),
+        Some(x) => return fail(format!("mapped to {:?} instead of {:?} via fixture procfs", x.path, expected)),
+        None => return fail(format!("no mapping via fixture procfs {:?}", fixture)),
+    }
+    println!("selftest OK: fixture procfs {:?} maps pid {} to {:?}", fixture, pid, expected);
+    Ok(())
+}
+
+/// Number of fake processes, each in its own network namespace, in the `soak` fixture procfs
+const SOAK_PROCESSES: u32 = 64;
+/// Resident memory growth tolerated by `soak` between the end of warm-up and the last iteration
+const SOAK_MAX_RSS_GROWTH: u64 = 8 * 1024 * 1024;
+
+/// Resident set size and number of open file descriptors of own process
+fn soak_usage(procfs: &Path) -> std::io::Result<(u64, usize)> {
+    let statm = std::fs::read_to_string(procfs.join("self/statm"))?;
+    let pages: u64 = statm.split_whitespace().nth(1).and_then(|x| x.parse().ok()).unwrap_or(0);
+    let page_size = nix::unistd::sysconf(nix::unistd::SysconfVar::PAGE_SIZE).ok().flatten().unwrap_or(4096) as u64;
+    let fds = std::fs::read_dir(procfs.join("self/fd"))?.count();
+    Ok((pages * page_size, fds))
+}
+
+/// `resolvconffs soak [--iterations N]`: map requests of fake processes over and over and check that memory and descriptors do not leak.
+/// Maintainer tool, not listed in usage.
+fn soak(args: Vec<String>) -> std::io::Result<()> {
+    let iterations: u64 = match args.as_slice() {
+        [] => 100_000,
+        [flag, n] if flag == "--iterations" => n.parse().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("--iterations: {}", e)))?,
+        _ => return Err(std::io::Error::new(std::io::ErrorKind::Other, "usage: resolvconffs soak [--iterations N]")),
+    };
+    let dir = std::env::temp_dir().join(format!("resolvconffs-soak-{}", std::process::id()));
+    std::fs::create_dir(&dir)?;
+    let result = soak_in(&dir, iterations);
+    let _ = std::fs::remove_dir_all(&dir);
+    result
+}
+
+fn soak_in(dir: &Path, iterations: u64) -> std::io::Result<()> {
+    let fail = |msg: String| Err(std::io::Error::new(std::io::ErrorKind::Other, format!("soak failed: {}", msg)));
+
+    let backing_directory = dir.join("backing");
+    let fixture = dir.join("proc");
+    std::fs::create_dir(&backing_directory)?;
+    let first_pid = 2_000_000;
+    for pid in first_pid..first_pid + SOAK_PROCESSES {
+        let piddir = fixture.join(format!("{}", pid));
+        std::fs::create_dir_all(piddir.join("ns"))?;
+        std::os::unix::fs::symlink(format!("net:[{}]", 4_000_000 + pid), piddir.join("ns/net"))?;
+        std::fs::write(piddir.join("status"), format!("Name:\tsoak\nTgid:\t{}\nPid:\t{}\n", pid, pid))?;
+        std::fs::write(piddir.join("stat"), format!("{} (soak) S{}\n", pid, " 0".repeat(20)))?;
+    }
+
+    let args = [
+        backing_directory.to_string_lossy().into_owned(),
+        dir.join("mountpoint").to_string_lossy().into_owned(),
+        "--default-content".to_owned(),
+        SELFTEST_CONTENT.to_owned(),
+    ];
+    let mut opts = Opts::parse_args_default(&args).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+    opts.merge_env()?;
+    let own_procfs = opts.procfs().to_owned();
+    opts.procfs = Some(fixture);
+    let mut mapper = NetnsMapper::from_opts(&opts)?;
+    // Short TTL, so that both cache hits and fresh lookups are exercised
+    let mut mapper = CachingMapper::new(move |rq| mapper.map(rq), Duration::from_millis(50));
+
+    let report_every = (iterations / 10).max(1);
+    let mut baseline = None;
+    let mut usage = soak_usage(&own_procfs)?;
+    for i in 0..iterations {
+        let rq = UidGidPid {
+            uid: (i % 3) as u32,
+            gid: 0,
+            pid: first_pid + (i % u64::from(SOAK_PROCESSES)) as u32,
+            write: i % 2 == 0,
+        };
+        if mapper.map(rq).is_none() {
+            return fail(format!("no mapping for pid {} at iteration {}", rq.pid, i));
+        }
+        if (i + 1) % report_every == 0 {
+            usage = soak_usage(&own_procfs)?;
+            println!(
+                "soak: {} iterations, rss {} KiB, {} fds, cache {} hits {} misses",
+                i + 1,
+                usage.0 / 1024,
+                usage.1,
+                mapper.hits(),
+                mapper.misses(),
+            );
+            // The first period is warm-up: caches and allocator pools fill up there
+            baseline.get_or_insert(usage);
+        }
+    }
+
+    let (rss, fds) = baseline.unwrap_or(usage);
+    if usage.1 > fds {
+        return fail(format!("open descriptors grew from {} to {}", fds, usage.1));
+    }
+    if usage.0 > rss + SOAK_MAX_RSS_GROWTH {
+        return fail(format!("resident memory grew from {} KiB to {} KiB", rss / 1024, usage.0 / 1024));
+    }
+    println!("soak OK: {} iterations without descriptor or memory growth", iterations);
+    Ok(())
+}
+
+/// Mountpoint files up to this size are checked to be unchanged after unmount
+const MOUNTPOINT_CHECK_MAX_SIZE: u64 = 1024 * 1024;
+
+fn main() -> std::io::Result<()> {
+    #[cfg(feature="logging")]
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+
+    if std::env::args().nth(1).as_deref() == Some("selftest") {
+        return selftest();
+    }
+    if std::env::args().nth(1).as_deref() == Some("soak") {
+        return soak(std::env::args().skip(2).collect());
+    }
+    if std::env::args().nth(1).as_deref() == Some("probe") {
+        return probe();
+    }
+    if std::env::args().nth(1).as_deref() == Some("print-unit") {
+        return print_unit(std::env::args().skip(2).collect());
+    }
+
+    let mut opts: Opts = gumdrop::parse_args_or_exit(gumdrop::ParsingStyle::AllOptions);
+    opts.merge_env()?;
+    if opts.print_config {
+        println!("{}", opts.config_json());
+        return Ok(());
+    }
+
+    // Everything below, including mounting, happens in the joined namespaces
+    for path in &opts.setns {
+        join_namespace(path)?;
+    }
+
+    let mut mapper = NetnsMapper::from_opts(&opts)?;
+    if opts.policy_file.is_some() || opts.default_file.is_some() || !opts.default_file_for.is_empty() || opts.audit_log.is_some() {
+        install_sighup_handler()?;
+    }
+
+    let sanity_level = if opts.strict {
+        log::Level::Error
+    } else if opts.quiet {
+        log::Level::Debug
+    } else {
+        log::Level::Warn
+    };
+    let mut sane = mapper.sanity_check(sanity_level);
+    if opts.case_sensitive_check {
+        for dir in std::iter::once(opts.backing_directory()).chain(opts.upper_dir.as_deref()) {
+            match is_case_insensitive(dir) {
+                Ok(false) => (),
+                Ok(true) => {
+                    log::log!(sanity_level, "{:?} is on a case-insensitive filesystem, identifiers differing only in case would share a file", dir);
+                    sane = false;
+                }
+                Err(e) => {
+                    log::log!(sanity_level, "Cannot check case sensitivity of {:?}: {}", dir, e);
+                    sane = false;
+                }
+            }
+        }
+    }
+
+    let cpus = opts.cpu_affinity.as_deref().map(parse_cpu_list).transpose()?;
+    // Threads are found in `<procfs>/self/task`, which a directory standing in for procfs does not describe
+    if (cpus.is_some() || opts.nice.is_some() || opts.rt_priority.is_some()) && !is_procfs(opts.procfs()) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("--cpu-affinity, --nice and --rt-priority need --procfs {:?} to be a mounted procfs", opts.procfs()),
+        ));
+    }
+
+    if opts.prewarm {
+        mapper.prewarm();
+    }
+    let fuse_opts = fuse_mount_options(&opts);
+    let mut mapper = CachingMapper::new(
+        move |rq| mapper.map(rq),
+        Duration::from_millis(opts.cache_ttl_ms),
+    );
+    mapper.set_fresh_writes(opts.fresh_on_write_open);
+    let fs_opts = fs_options(&opts)?;
+    let fs = FileMapperFs::with_options(move |rq| mapper.map(rq), fs_opts);
+
+    let mut mountpoint = opts.mountpoint_file().to_owned();
+    if let Some(fd) = opts.mountpoint_fd {
+        if let Err(e) = nix::fcntl::fcntl(fd, nix::fcntl::FcntlArg::F_GETFD) {
+            log::error!("--mountpoint-fd {} is not an open descriptor: {}", fd, e);
+            std::process::exit(1);
+        }
+        if !nix::unistd::geteuid().is_root() {
+            log::error!("--mountpoint-fd needs root: without it fuser mounts via fusermount, which checks mountpoints by path and refuses /proc/self/fd/{}", fd);
+            std::process::exit(1);
+        }
+    }
+    // `/proc/self/fd/<fd>` is a symlink too, but mounting follows it to the open file itself
+    if opts.mountpoint_fd.is_none() && std::fs::symlink_metadata(&mountpoint).map(|x| x.file_type().is_symlink()).unwrap_or(false) {
+        if !opts.resolve_mountpoint_symlink {
+            log::error!("{:?} is a symlink. Mounting over it would shadow the symlink's target for everybody or behave unexpectedly.\nPass --resolve-mountpoint-symlink to mount over the target file, or replace the symlink with a regular file.", mountpoint);
+            std::process::exit(1);
+        }
+        mountpoint = std::fs::canonicalize(&mountpoint)?;
+        log::info!("Mounting over symlink target {:?}", mountpoint);
+    }
+
+    if !mountpoint.exists() && on_read_only_fs(&mountpoint) {
+        log::error!("{:?} does not exist and cannot be created, as it is on a read-only filesystem.\nCreate an empty file there when building the image, or mount a tmpfs over its directory, or bind-mount some file over it before starting resolvconffs.", mountpoint);
+        std::process::exit(1);
+    }
+    if on_read_only_fs(&mountpoint) && opts.mount_via.is_none() {
+        log::info!("{:?} is on a read-only filesystem. If mounting fails, try --mount-via with a file on a writable filesystem like /run.", mountpoint);
+    }
+
+    let mountpoint_meta = if opts.mountpoint_fd.is_some() {
+        std::fs::metadata(&mountpoint)
+    } else {
+        std::fs::symlink_metadata(&mountpoint)
+    };
+    if mountpoint_meta.map(|x| x.is_file()).ok() != Some(true) {
+        log::log!(sanity_level, "Use regular file as a mountpoint, not a directory.");
+        sane = false;
+    }
+
+    if opts.strict && !sane {
+        log::error!("Exiting due to --strict");
+        std::process::exit(1);
+    }
+
+    let per_uid_defaults = opts
+        .default_file_for
+        .iter()
+        .map(|x| parse_uid_path(x).map(|(_, path)| path))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    let mut dependencies = vec![opts.backing_directory(), opts.procfs(), opts.netns_dir.as_path()];
+    dependencies.extend(per_uid_defaults.iter().map(PathBuf::as_path));
+    for x in [
+        &opts.default_file,
+        &opts.upper_dir,
+        &opts.mirror_dir,
+        &opts.policy_file,
+        &opts.id_map_file,
+        &opts.read_prefix,
+        &opts.host_file,
+    ] {
+        dependencies.extend(x.as_deref());
+    }
+    if let Some(dep) = depends_on_mountpoint(&mountpoint, &dependencies) {
+        log::error!("{:?} is at or under the mountpoint {:?}. resolvconffs would be serving requests to itself and hang.", dep, mountpoint);
+        std::process::exit(1);
+    }
+
+    // The file we mount over should be left intact. Remember it to catch accidental writes to it.
+    // Mountpoint is not reachable from chroot after unmounting
+    let original_content = std::fs::metadata(&mountpoint)
+        .ok()
+        .filter(|_| opts.chroot.is_none())
+        .filter(|x| x.is_file() && x.len() <= MOUNTPOINT_CHECK_MAX_SIZE)
+        .and_then(|_| std::fs::read(&mountpoint).ok());
+
+    let notify_socket = connect_notify_socket();
+    let result = {
+        let mut _bind = None;
+        let session = match opts.mount_via {
+            Some(ref via) => {
+                if !via.exists() {
+                    std::fs::File::create(via)?;
+                }
+                let session = spawn_mount2(fs, via, &fuse_opts)?;
+                // On failure dropping `session` unmounts `via`. The mountpoint is left alone, something mounted there beforehand is not ours.
+                nix::mount::mount(Some(via.as_path()), &mountpoint, None::<&str>, nix::mount::MsFlags::MS_BIND, None::<&str>)?;
+                // Detached on every way out of this block: errors below and the end of the session
+                _bind = Some(BindMount(mountpoint.clone()));
+                session
+            }
+            None => spawn_mount2(fs, &mountpoint, &fuse_opts)?,
+        };
+        apply_scheduling(&opts, cpus.as_ref())?;
+        if let Some(ref dir) = opts.chroot {
+            let required = chroot_required(&opts)?;
+            enter_chroot(dir, &required.iter().map(PathBuf::as_path).collect::<Vec<_>>())?;
+        }
+        sd_notify(&notify_socket, "READY=1");
+        let fuser::BackgroundSession { guard, .. } = session;
+        guard
+            .join()
+            .unwrap_or_else(|_| Err(std::io::Error::new(std::io::ErrorKind::Other, "FUSE session thread panicked")))
+    };
+    sd_notify(&notify_socket, "STOPPING=1");
+
+    if let Some(original_content) = original_content {
+        match std::fs::read(&mountpoint) {
+            Ok(x) if x == original_content => (),
+            Ok(_) => log::error!("Content of {:?} changed while resolvconffs was mounted over it", mountpoint),
+            Err(e) => log::warn!("Cannot re-read {:?} after unmount: {}", mountpoint, e),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::AsRawFd;
+
+    /// Mapper returning `/<n>` for its n-th call, and the call counter
+    fn counting_mapper() -> (impl Mapper, std::sync::Arc<std::sync::atomic::AtomicU32>) {
+        let calls = std::sync::Arc::new(std::sync::atomic::AtomicU32::new(0));
+        let counter = calls.clone();
+        let mapper = move |_| {
+            let n = counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1;
+            Some(Mapping::from(PathBuf::from(format!("/{}", n))))
+        };
+        (mapper, calls)
+    }
+
+    fn rq(pid: u32, write: bool) -> UidGidPid {
+        UidGidPid { uid: 1000, gid: 1000, pid, write }
+    }
+
+    #[test]
+    fn caching_mapper_expires_entries() {
+        let (inner, calls) = counting_mapper();
+        let mut mapper = CachingMapper::new(inner, Duration::from_millis(50));
+        assert_eq!(mapper.map(rq(1, false)).unwrap().path, Path::new("/1"));
+        assert_eq!(mapper.map(rq(1, false)).unwrap().path, Path::new("/1"));
+        assert_eq!(mapper.map(rq(2, false)).unwrap().path, Path::new("/2"));
+        assert_eq!((mapper.hits(), mapper.misses()), (1, 2));
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(mapper.map(rq(1, false)).unwrap().path, Path::new("/3"));
+        assert_eq!((mapper.hits(), mapper.misses()), (1, 3));
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn caching_mapper_does_not_remember_failures() {
+        let mut fail = true;
+        let mut mapper = CachingMapper::new(
+            move |_| {
+                let x = (!fail).then(|| Mapping::from(PathBuf::from("/ok")));
+                fail = false;
+                x
+            },
+            Duration::from_secs(3600),
+        );
+        assert_eq!(mapper.map(rq(1, false)), None);
+        assert_eq!(mapper.map(rq(1, false)).unwrap().path, Path::new("/ok"));
+    }
+
+    /// Fresh temporary directory, removed when the returned guard is dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let dir = std::env::temp_dir().join(format!("resolvconffs-test-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn id_map_parsing() {
+        let dir = TempDir::new("idmap");
+        let file = dir.0.join("idmap");
+        std::fs::write(&file, "# comment\n4026532413 web\n\n 4026532414  db \n").unwrap();
+        let map = load_id_map(&file).unwrap();
+        assert_eq!(map.len(), 2);
+        assert_eq!(map["4026532414"], "db");
+        for bad in ["1", "1 a/b", "1 ..", "1 a b"] {
+            std::fs::write(&file, bad).unwrap();
+            assert!(load_id_map(&file).is_err(), "{:?} accepted", bad);
+        }
+    }
+
+    fn opts(args: &[&str]) -> Opts {
+        let mut args: Vec<String> = args.iter().map(|x| x.to_string()).collect();
+        args.extend(["/backing".to_owned(), "/mountpoint".to_owned()]);
+        Opts::parse_args_default(&args).unwrap()
+    }
+
+    #[test]
+    fn fuse_opt_parsing() {
+        use fuser::MountOption;
+        assert_eq!(parse_fuse_opt("ro"), MountOption::RO);
+        assert_eq!(parse_fuse_opt("allow_root"), MountOption::AllowRoot);
+        assert_eq!(parse_fuse_opt("fsname=rcfs"), MountOption::FSName("rcfs".to_owned()));
+        assert_eq!(parse_fuse_opt("subtype=x"), MountOption::Subtype("x".to_owned()));
+        assert_eq!(parse_fuse_opt("max_read=4096"), MountOption::CUSTOM("max_read=4096".to_owned()));
+    }
+
+    #[test]
+    fn mount_options_allow_other_by_default() {
+        let x = fuse_mount_options(&opts(&[]));
+        assert!(x.contains(&fuser::MountOption::AllowOther));
+        assert!(x.contains(&fuser::MountOption::DefaultPermissions));
+        let x = fuse_mount_options(&opts(&["--no-default-permissions-for-root"]));
+        assert!(x.contains(&fuser::MountOption::DefaultPermissions));
+    }
+
+    #[test]
+    fn mount_options_allow_root_replaces_allow_other() {
+        let x = fuse_mount_options(&opts(&["-o", "allow_root,noexec"]));
+        assert!(x.contains(&fuser::MountOption::AllowRoot));
+        assert!(!x.contains(&fuser::MountOption::AllowOther));
+        assert!(x.contains(&fuser::MountOption::NoExec));
+    }
+
+    #[test]
+    fn uid_path_parsing() {
+        assert_eq!(parse_uid_path("1000:/etc/x.conf").unwrap(), (1000, PathBuf::from("/etc/x.conf")));
+        assert!(parse_uid_path("1000:").is_err());
+        assert!(parse_uid_path("x:/etc/x.conf").is_err());
+    }
+
+    /// Backing directory, default file and a directory standing in for procfs, with `pids` in netns `net:[<pid>0]`.
+    /// Link content of the last pid has a trailing newline.
+    fn netns_fixture(dir: &Path, pids: &[u32], extra_args: &[&str]) -> NetnsMapper {
+        let backing = dir.join("backing");
+        std::fs::create_dir_all(&backing).unwrap();
+        std::fs::write(dir.join("default.conf"), "nameserver 127.0.0.1\n").unwrap();
+        for (i, pid) in pids.iter().enumerate() {
+            let ns = dir.join(format!("proc/{}/ns", pid));
+            std::fs::create_dir_all(&ns).unwrap();
+            let newline = if i + 1 == pids.len() { "\n" } else { "" };
+            std::os::unix::fs::symlink(format!("net:[{}0]{}", pid, newline), ns.join("net")).unwrap();
+        }
+        let mut args: Vec<String> = extra_args.iter().map(|x| x.to_string()).collect();
+        args.extend([
+            "--default-file".to_owned(),
+            dir.join("default.conf").to_string_lossy().into_owned(),
+            "--procfs".to_owned(),
+            dir.join("proc").to_string_lossy().into_owned(),
+            backing.to_string_lossy().into_owned(),
+            dir.join("mountpoint").to_string_lossy().into_owned(),
+        ]);
+        let opts = Opts::parse_args_default(&args).unwrap();
+        NetnsMapper::from_opts(&opts).unwrap()
+    }
+
+    #[test]
+    fn netns_mapping_via_fixture_procfs() {
+        let dir = TempDir::new("netns");
+        let mut mapper = netns_fixture(&dir.0, &[100, 200], &[]);
+        assert!(!is_procfs(&dir.0.join("proc")));
+        let x = mapper.map(rq(100, false)).unwrap();
+        assert_eq!(x.path, dir.0.join("backing/1000.conf"));
+        assert!(!x.read_only);
+        // Trailing whitespace in the link does not end up in the file name
+        assert_eq!(mapper.map(rq(200, false)).unwrap().path, dir.0.join("backing/2000.conf"));
+        assert_eq!(mapper.map(rq(300, false)), None);
+    }
 
-use gumdrop::Options;
-use nix::fcntl::OFlag;
+    #[test]
+    fn owner_parsing() {
+        assert_eq!(parse_owner("0:100").unwrap(), (0, 100));
+        assert!(parse_owner("0").is_err());
+        assert!(parse_owner("0:x").is_err());
+    }
 
-/// Special FUSE filesystem that maps its sole file to other files based on network namespace of process that queries the file.
-/// To be used for /etc/resolv.conf in setups where network namespaces are used without accompanying mount namespaces (without /etc/netns)
-#[derive(Options)]
-struct Opts {
-    help: bool,
+    #[test]
+    fn attr_ttl_follows_option() {
+        assert_eq!(fs_options(&opts(&[])).unwrap().attr_ttl, Duration::ZERO);
+        assert_eq!(fs_options(&opts(&["--attr-timeout-ms", "250"])).unwrap().attr_ttl, Duration::from_millis(250));
+    }
 
-    /// Directory where to look for resolv.conf-like files for each netns.
-    #[options(free, required)]
-    backing_directory: PathBuf,
+    #[test]
+    fn served_file_is_the_fuse_root() {
+        assert_eq!(FILE_INO, fuser::FUSE_ROOT_ID);
+        assert_eq!(FILE_INO, 1);
+    }
 
-    /// Filename extension. resolvconffs maps its file to <backing_directory>/<netns_identifier><postfix>
-    #[options(short = 'p', default = "conf")]
-    extension: PathBuf,
+    #[test]
+    fn merge_env_checks_mountpoint_arguments() {
+        let mut x = opts(&[]);
+        x.merge_env().unwrap();
+        assert_eq!(x.backing_directory(), Path::new("/backing"));
+        assert_eq!(x.mountpoint_file(), Path::new("/mountpoint"));
 
-    /// In case of target file does not exist, copy this file to target instead of returning ENOENT.
-    #[options(short = 'd')]
-    default_file: Option<PathBuf>,
+        let mut x = opts(&["--mountpoint-fd", "3"]);
+        assert_eq!(x.merge_env().unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
 
-    /// Directory where to look up network namespace IDs based on PIDs.
-    #[options(short = 'P', default = "/proc")]
-    procfs: PathBuf,
+        let mut x = Opts::parse_args_default(&["--mountpoint-fd", "3", "--procfs", "/fixture", "/backing"]).unwrap();
+        x.merge_env().unwrap();
+        assert_eq!(x.mountpoint_file(), Path::new("/proc/self/fd/3"));
+    }
 
-    #[options(free, required)]
-    mountpoint_file: PathBuf,
+    #[test]
+    fn systemd_quoting() {
+        assert_eq!(systemd_quote("/usr/bin/resolvconffs"), "/usr/bin/resolvconffs");
+        assert_eq!(systemd_quote(""), r#""""#);
+        assert_eq!(systemd_quote("a b"), r#""a b""#);
+        assert_eq!(systemd_quote("100%"), r#""100%%""#);
+        assert_eq!(systemd_quote("$HOME"), r#""$$HOME""#);
+    }
 
-    #[options(short = 'o', long = "fuse-opt")]
-    other_fuse_opts: Vec<String>,
-}
+    /// Reader and writer failing with `Interrupted` on every other call and moving at most 3 bytes at a time
+    struct Flaky<T> {
+        inner: T,
+        calls: u32,
+    }
 
-#[derive(Copy, Clone, PartialEq, PartialOrd, Ord, Eq, Debug, Hash)]
-pub struct UidGidPid {
-    pub uid: u32,
-    pub gid: u32,
-    pub pid: u32,
-}
+    impl<T> Flaky<T> {
+        fn interrupt(&mut self) -> bool {
+            self.calls += 1;
+            self.calls % 2 == 1
+        }
+    }
 
-trait_set::trait_set! {
-    pub trait Mapper = FnMut(UidGidPid) -> Option<PathBuf>;
-}
+    impl<T: std::io::Read> std::io::Read for Flaky<T> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.interrupt() {
+                return Err(std::io::ErrorKind::Interrupted.into());
+            }
+            let len = buf.len().min(3);
+            self.inner.read(&mut buf[..len])
+        }
+    }
 
-pub struct FileMapperFs<F: Mapper> {
-    mapper: F,
-}
+    impl<T: std::io::Write> std::io::Write for Flaky<T> {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.interrupt() {
+                return Err(std::io::ErrorKind::Interrupted.into());
+            }
+            self.inner.write(&buf[..buf.len().min(2)])
+        }
 
-impl<F: Mapper> FileMapperFs<F> {
-    fn get_backing_file(&mut self, rq: &fuser::Request<'_>) -> nix::Result<PathBuf> {
-        match (self.mapper)(UidGidPid {
-            uid: rq.uid(),
-            gid: rq.gid(),
-            pid: rq.pid(),
-        }) {
-            Some(x) => Ok(x),
-            None => Err(nix::errno::Errno::ENOENT),
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
         }
     }
 
-    pub fn new(mapper: F) -> Self {
-        Self { mapper }
+    #[test]
+    fn copy_fully_survives_interruptions_and_short_writes() {
+        let data = b"nameserver 127.0.0.53\noptions edns0\n";
+        let mut src = Flaky { inner: &data[..], calls: 0 };
+        let mut dst = Flaky { inner: Vec::new(), calls: 0 };
+        assert_eq!(copy_fully(&mut src, &mut dst).unwrap(), data.len() as u64);
+        assert_eq!(dst.inner, data);
     }
-}
 
-macro_rules! nftry {
-    ($e:expr, $reply:ident) => {
-        match $e {
-            Ok(x) => x,
-            Err(e) => return $reply.error(e as i32),
-        }
-    };
-}
+    #[test]
+    fn memory_handles_count_against_handle_limit() {
+        let opts = FsOptions {
+            max_open_handles: Some(1),
+            ..FsOptions::default()
+        };
+        let mut fs = FileMapperFs::with_options(|_| None, opts);
+        let content: Arc<[u8]> = b"nameserver 127.0.0.1\n"[..].into();
+        let fh = fs.register_memory_handle(content.clone(), Path::new("/a"), 0).unwrap();
+        assert_eq!(fs.register_memory_handle(content.clone(), Path::new("/b"), 0), Err(nix::errno::Errno::EMFILE));
+        fs.unregister_handle(fh).unwrap();
+        assert!(fs.register_memory_handle(content, Path::new("/b"), 0).is_ok());
+    }
 
-fn getattr_impl(f: impl AsRef<Path>, ino: u64, reply: fuser::ReplyAttr) {
-    let st = nftry!(nix::sys::stat::stat(f.as_ref()), reply);
+    #[test]
+    fn shard_name_matches_readme_example() {
+        assert_eq!(shard_name("4026532413"), "eb");
+    }
 
-    reply.attr(
-        &Duration::from_millis(0),
-        &fuser::FileAttr {
-            ino,
-            size: st.st_size as u64,
-            blocks: st.st_blocks as u64,
-            atime: SystemTime::UNIX_EPOCH
-                + Duration::new(st.st_atime as u64, st.st_atime_nsec as u32),
-            mtime: SystemTime::UNIX_EPOCH
-                + Duration::new(st.st_mtime as u64, st.st_mtime_nsec as u32),
-            ctime: SystemTime::UNIX_EPOCH
-                + Duration::new(st.st_ctime as u64, st.st_ctime_nsec as u32),
-            crtime: SystemTime::UNIX_EPOCH, // https://github.com/nix-rust/nix/issues/1649
-            kind: fuser::FileType::RegularFile,
-            perm: st.st_mode as u16,
-            nlink: 1,
-            uid: st.st_uid,
-            gid: st.st_gid,
-            rdev: 0,
-            blksize: st.st_blksize as u32,
-            flags: 0,
-        },
-    );
-}
+    #[test]
+    fn policy_file_parsing() {
+        let dir = TempDir::new("policy");
+        let file = dir.0.join("policy");
+        std::fs::write(&file, "# comment\n\n  web ro\nuid:1000 rw\ndb rw\n").unwrap();
+        let policy = Policy::load(&file, true).unwrap();
+        assert!(policy.read_only("web", 0));
+        assert!(!policy.read_only("db", 0));
+        assert!(policy.read_only("other", 0), "default applies to unlisted identifiers");
+        assert!(!policy.read_only("web", 1000), "uid entries take precedence");
+        assert!(policy.lists("other", 1000));
+        assert!(!policy.lists("other", 0));
 
-impl<F: Mapper> fuser::Filesystem for FileMapperFs<F> {
-    fn getattr(&mut self, _req: &fuser::Request<'_>, ino: u64, reply: fuser::ReplyAttr) {
-        if ino == 1 {
-            let bf = nftry!(self.get_backing_file(_req), reply);
-            getattr_impl(bf, ino, reply);
-        } else {
-            reply.error(libc::ENOENT)
+        for bad in ["web", "web maybe", "web ro extra", "uid:x ro"] {
+            std::fs::write(&file, bad).unwrap();
+            let e = Policy::load(&file, false).err().expect("accepted");
+            assert_eq!(e.kind(), std::io::ErrorKind::InvalidData, "{:?}: {}", bad, e);
         }
     }
 
-    fn open(&mut self, _req: &fuser::Request<'_>, ino: u64, flags: i32, reply: fuser::ReplyOpen) {
-        if ino != 1 {
-            return reply.error(libc::ENOENT);
+    #[test]
+    fn policy_makes_mapping_read_only() {
+        let dir = TempDir::new("netns-policy");
+        let policy = dir.0.join("policy");
+        std::fs::write(&policy, "1000 ro\n").unwrap();
+        let policy = policy.to_string_lossy().into_owned();
+        let mut mapper = netns_fixture(&dir.0, &[100, 200], &["--policy-file", &policy]);
+        assert!(mapper.map(rq(100, true)).unwrap().read_only);
+        assert!(!mapper.map(rq(200, true)).unwrap().read_only);
+    }
+
+    #[test]
+    fn unknown_handles_are_ebadf() {
+        let mut fs = FileMapperFs::with_options(|_| None, FsOptions::default());
+        assert_eq!(fs.check_handle(12345), Err(nix::errno::Errno::EBADF));
+        assert_eq!(fs.unregister_handle(12345), Err(nix::errno::Errno::EBADF));
+    }
+
+    #[test]
+    fn chroot_required_includes_paths_used_after_startup() {
+        let x = chroot_required(&opts(&[
+            "--policy-file", "/etc/policy",
+            "--audit-log", "/var/log/rcfs/audit.log",
+            "--key-source", "pidfile:/run/profiles",
+            "--default-file-for", "1000:/etc/user.conf",
+            "--id-map-file", "/etc/idmap",
+        ]))
+        .unwrap();
+        for path in ["/backing", "/etc/policy", "/var/log/rcfs", "/run/profiles", "/etc/user.conf"] {
+            assert!(x.contains(&PathBuf::from(path)), "{} missing from {:?}", path, x);
         }
-        let bf = nftry!(self.get_backing_file(_req), reply);
+        assert!(!x.contains(&PathBuf::from("/etc/idmap")));
+    }
 
-        match nix::fcntl::open(
-            &bf,
-            OFlag::from_bits_truncate(flags),
-            nix::sys::stat::Mode::from_bits_truncate(0o666),
-        ) {
-            Ok(fh) => {
-                return reply.opened(fh as u64, fuser::consts::FOPEN_DIRECT_IO);
-            }
-            Err(e) => {
-                return reply.error(e as i32);
-            }
+    #[test]
+    fn netns_options_need_netns_key_source() {
+        for flag in [&["--init-netns-readonly"][..], &["--host-file", "/etc/resolv.conf"][..]] {
+            let mut args = vec!["--key-source", "pidfile:/run/profiles"];
+            args.extend(flag);
+            let e = NetnsMapper::from_opts(&opts(&args)).err().expect("accepted");
+            assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput, "{}", e);
         }
     }
 
-    fn release(
-        &mut self,
-        _req: &fuser::Request<'_>,
-        _ino: u64,
-        _fh: u64,
-        _flags: i32,
-        _lock_owner: Option<u64>,
-        _flush: bool,
-        reply: fuser::ReplyEmpty,
-    ) {
-        let fh = _fh as i32;
-        match nix::unistd::close(fh) {
-            Ok(()) => return reply.ok(),
-            Err(e) => return reply.error(e as i32),
+    #[test]
+    fn default_policy_needs_policy_file() {
+        let e = NetnsMapper::from_opts(&opts(&["--default-policy", "ro"])).err().expect("accepted");
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput, "{}", e);
+        assert!(e.to_string().contains("--policy-file"), "{}", e);
+
+        let dir = TempDir::new("defaultpolicy");
+        let policy = dir.0.join("policy");
+        std::fs::write(&policy, "1000 rw\n").unwrap();
+        let mut mapper = netns_fixture(&dir.0, &[100, 200], &["--policy-file", policy.to_str().unwrap(), "--default-policy", "ro"]);
+        assert!(!mapper.map(rq(100, false)).unwrap().read_only);
+        assert!(mapper.map(rq(200, false)).unwrap().read_only);
+    }
+
+    #[test]
+    fn notify_socket_receives_state() {
+        let dir = TempDir::new("notify");
+        let path = dir.0.join("notify");
+        let systemd = std::os::unix::net::UnixDatagram::bind(&path).unwrap();
+        let abstract_name = format!("resolvconffs-test-notify-{}", std::process::id());
+        let abstract_addr = nix::sys::socket::UnixAddr::new_abstract(abstract_name.as_bytes()).unwrap();
+        let abstract_fd = nix::sys::socket::socket(
+            nix::sys::socket::AddressFamily::Unix,
+            nix::sys::socket::SockType::Datagram,
+            nix::sys::socket::SockFlag::SOCK_CLOEXEC,
+            None,
+        )
+        .unwrap();
+        nix::sys::socket::bind(abstract_fd, &nix::sys::socket::SockAddr::Unix(abstract_addr)).unwrap();
+
+        // The only test touching NOTIFY_SOCKET, so setting it does not race with others
+        for (value, fd) in [(path.as_os_str().to_owned(), systemd.as_raw_fd()), (format!("@{}", abstract_name).into(), abstract_fd)] {
+            std::env::set_var("NOTIFY_SOCKET", &value);
+            let sock = connect_notify_socket();
+            assert!(sock.is_some(), "cannot connect to {:?}", value);
+            sd_notify(&sock, "READY=1");
+            let mut buf = [0u8; 16];
+            let n = nix::sys::socket::recv(fd, &mut buf, nix::sys::socket::MsgFlags::empty()).unwrap();
+            assert_eq!(&buf[..n], b"READY=1");
         }
+        std::env::remove_var("NOTIFY_SOCKET");
+        let _ = nix::unistd::close(abstract_fd);
     }
 
-    fn fsync(
-        &mut self,
-        _req: &fuser::Request<'_>,
-        _ino: u64,
-        _fh: u64,
-        datasync: bool,
-        reply: fuser::ReplyEmpty,
-    ) {
-        let fh = _fh as i32;
-        if datasync {
-            match nix::unistd::fdatasync(fh) {
-                Ok(()) => return reply.ok(),
-                Err(e) => return reply.error(e as i32),
-            }
-        } else {
-            match nix::unistd::fsync(fh) {
-                Ok(()) => return reply.ok(),
-                Err(e) => return reply.error(e as i32),
-            }
+    #[test]
+    fn extension_validation() {
+        for ext in ["", "conf", ".conf", "resolv.conf"] {
+            assert!(validate_extension(OsStr::new(ext)).is_ok(), "{:?} rejected", ext);
+        }
+        for ext in ["a/b", "..", "../conf", "co nf", "conf\n"] {
+            assert!(validate_extension(OsStr::new(ext)).is_err(), "{:?} accepted", ext);
         }
     }
 
-    fn read(
-        &mut self,
-        _req: &fuser::Request<'_>,
-        _ino: u64,
-        _fh: u64,
-        offset: i64,
-        mut size: u32,
-        _flags: i32,
-        _lock_owner: Option<u64>,
-        reply: fuser::ReplyData,
-    ) {
-        let fh = _fh as i32;
-        size = size.min(4096 * 16);
-        let mut buf = vec![0u8; size as usize];
-        let ret = nftry!(nix::sys::uio::pread(fh, &mut buf[..], offset), reply);
-        reply.data(&buf[0..ret])
+    #[test]
+    fn op_set_parsing() {
+        let ops = OpSet::parse("read, getattr").unwrap();
+        assert!(ops.allows("read") && ops.allows("getattr"));
+        assert!(!ops.allows("write"));
+        assert!(OpSet::default().allows("write"));
+        assert!(OpSet::parse("mkdir").is_err());
     }
 
-    fn write(
-        &mut self,
-        _req: &fuser::Request<'_>,
-        _ino: u64,
-        _fh: u64,
-        offset: i64,
-        data: &[u8],
-        _write_flags: u32,
-        _flags: i32,
-        _lock_owner: Option<u64>,
-        reply: fuser::ReplyWrite,
-    ) {
-        let fh = _fh as i32;
-        let ret = nftry!(nix::sys::uio::pwrite(fh, data, offset), reply);
-        // FIXME: u32 overflow handling
-        reply.written(ret as u32)
+    #[test]
+    fn ino_table_is_stable_and_bounded() {
+        let mut table = InoTable::default();
+        let a = table.ino_for(Path::new("/backing/1.conf"));
+        assert!(a > FILE_INO);
+        assert_eq!(table.ino_for(Path::new("/backing/1.conf")), a);
+        assert_ne!(table.ino_for(Path::new("/backing/2.conf")), a);
+        for i in 0..INO_TABLE_MAX_ENTRIES + 10 {
+            table.ino_for(Path::new(&format!("/backing/{}.conf", i)));
+        }
+        assert!(table.by_path.len() <= INO_TABLE_MAX_ENTRIES);
+        assert_eq!(table.by_path.len(), table.by_ino.len());
     }
 
-    fn setattr(
-        &mut self,
-        _req: &fuser::Request<'_>,
-        ino: u64,
-        _mode: Option<u32>,
-        _uid: Option<u32>,
-        _gid: Option<u32>,
-        _size: Option<u64>,
-        _atime: Option<fuser::TimeOrNow>,
-        _mtime: Option<fuser::TimeOrNow>,
-        _ctime: Option<SystemTime>,
-        _fh: Option<u64>,
-        _crtime: Option<SystemTime>,
-        _chgtime: Option<SystemTime>,
-        _bkuptime: Option<SystemTime>,
-        _flags: Option<u32>,
-        reply: fuser::ReplyAttr,
-    ) {
-        if ino != 1 {
-            return reply.error(libc::ENOENT);
+    #[test]
+    fn ino_table_probes_past_collisions() {
+        let mut table = InoTable::default();
+        let paths: Vec<PathBuf> = (0..3).map(|i| PathBuf::from(format!("/backing/{}.conf", i))).collect();
+        let inos: Vec<u64> = paths.iter().map(|x| table.ino_with_hash(x, 1000)).collect();
+        assert_eq!(inos, [1000, 1001, 1002]);
+        // Colliding paths keep their numbers, whatever hash they are asked with later
+        for (path, ino) in paths.iter().zip(&inos) {
+            assert_eq!(table.ino_with_hash(path, 1000), *ino);
+            assert_eq!(table.ino_for(path), *ino);
         }
+        // Probing skips numbers taken by the earlier ones, wraps around and never hands out 0 or FILE_INO
+        assert_eq!(table.ino_with_hash(Path::new("/backing/3.conf"), 1001), 1003);
+        assert_eq!(table.ino_with_hash(Path::new("/backing/4.conf"), u64::MAX), u64::MAX);
+        assert_eq!(table.ino_with_hash(Path::new("/backing/5.conf"), u64::MAX), FILE_INO + 1);
+        assert_eq!(table.ino_with_hash(Path::new("/backing/6.conf"), 0), FILE_INO + 2);
+        assert_eq!(table.by_ino.len(), 7);
+        assert_eq!(table.by_path.len(), 7);
+    }
 
-        let bf = nftry!(self.get_backing_file(_req), reply);
+    #[test]
+    fn reported_ino_depends_on_hash_ino() {
+        let mut fs = FileMapperFs::with_options(|_| None, FsOptions::default());
+        assert_eq!(fs.reported_ino(Path::new("/backing/1.conf")), FILE_INO);
+        let opts = FsOptions {
+            hash_ino: true,
+            ..FsOptions::default()
+        };
+        let mut fs = FileMapperFs::with_options(|_| None, opts);
+        let a = fs.reported_ino(Path::new("/backing/1.conf"));
+        assert_ne!(a, FILE_INO);
+        assert_eq!(fs.reported_ino(Path::new("/backing/1.conf")), a);
+    }
 
-        if let Some(size) = _size {
-            if let Some(fh) = _fh {
-                let fh = fh as i32;
-                nftry!(nix::unistd::ftruncate(fh, size as i64), reply);
-            } else {
-                nftry!(nix::unistd::truncate(&bf, size as i64), reply);
-            }
+    #[test]
+    fn template_hash_follows_content() {
+        let dir = TempDir::new("template");
+        let default_file = dir.0.join("default.conf");
+        let target = dir.0.join("target.conf");
+        std::fs::write(&default_file, "nameserver 1.1.1.1\n").unwrap();
+        let template = Template::File(default_file.clone());
+        template.write_to(&target).unwrap();
+        let populated = Populated::of(&target, template.clone()).unwrap();
+        assert_eq!(template.hash().unwrap(), populated.hash);
+        std::fs::write(&default_file, "nameserver 9.9.9.9\n").unwrap();
+        assert_ne!(template.hash().unwrap(), populated.hash);
+        let content = Template::Content(b"nameserver 9.9.9.9\n"[..].into());
+        assert_eq!(content.hash().unwrap(), template.hash().unwrap());
+        std::fs::remove_file(&default_file).unwrap();
+        assert!(template.hash().is_err());
+    }
+
+    #[test]
+    fn procfs_detection() {
+        let dir = TempDir::new("procfs");
+        assert!(!is_procfs(&dir.0));
+        assert!(is_procfs(Path::new("/proc")));
+    }
+
+    #[test]
+    fn target_path_prefers_existing_extension() {
+        let dir = TempDir::new("find-target");
+        let exts = [OsString::from("conf"), OsString::from("txt")];
+        assert_eq!(find_target_path(&dir.0, "id", &exts, 0, None), Ok((dir.0.join("id.conf"), 0)));
+        assert_eq!(find_target_path(&dir.0, "id", &exts, 1, None), Ok((dir.0.join("id.txt"), 1)));
+        std::fs::write(dir.0.join("id.txt"), "").unwrap();
+        assert_eq!(find_target_path(&dir.0, "id", &exts, 0, None), Ok((dir.0.join("id.txt"), 1)));
+    }
+
+    #[test]
+    fn print_config_json() {
+        let json = opts(&["--attr-timeout-ms", "250", "--extension", "a\"b"]).config_json();
+        assert!(json.starts_with('{') && json.ends_with('}'), "{}", json);
+        for field in [r#""backing-directory":"/backing""#, r#""attr-timeout-ms":250"#, r#""extension":"a\"b""#] {
+            assert!(json.contains(field), "{} missing from {}", field, json);
         }
 
-        getattr_impl(bf, ino, reply);
+        let mut out = String::new();
+        json_string("a\\b\n", &mut out);
+        assert_eq!(out, r#""a\\b\u000a""#);
     }
-}
 
-struct NetnsMapper {
-    backing_directory: PathBuf,
-    extension: PathBuf,
-    default_file: Option<PathBuf>,
-    procfs: PathBuf,
-}
+    #[test]
+    fn write_denied_on_writable_fs_is_eacces() {
+        let dir = TempDir::new("denied");
+        let file = dir.0.join("x.conf");
+        std::fs::write(&file, "").unwrap();
+        assert_eq!(write_denied_errno(&file), libc::EACCES);
+        assert_eq!(write_denied_errno(&dir.0.join("missing.conf")), libc::EACCES);
+    }
 
-impl NetnsMapper {
-    fn sanity_check(&self) {
-        if std::fs::metadata(&self.backing_directory)
-            .map(|x| x.is_dir())
-            .ok()
-            != Some(true)
-        {
-            #[cfg(feature="logging")]
-            eprintln!(
-                "Backing directory {:?} may be not accessible",
-                self.backing_directory
-            );
+    #[test]
+    fn cpu_list_parsing() {
+        let cpus = parse_cpu_list("0-2, 5").unwrap();
+        for cpu in 0..8 {
+            assert_eq!(cpus.is_set(cpu).unwrap(), matches!(cpu, 0..=2 | 5), "cpu {}", cpu);
         }
+        assert!(parse_cpu_list("3-1").is_err());
+        assert!(parse_cpu_list("x").is_err());
+    }
 
-        if let Some(ref deffile) = self.default_file {
-            if std::fs::File::open(deffile).is_err() {
-                #[cfg(feature="logging")]
-                eprintln!("Default file {:?} may be unopeneable", deffile);
+    #[test]
+    fn crlf_conversion() {
+        assert_eq!(to_crlf(b"a\nb\r\n\n"), b"a\r\nb\r\n\r\n");
+        assert_eq!(to_crlf(b"\n"), b"\r\n");
+        assert_eq!(from_crlf(b"a\r\nb\r\n\r"), b"a\nb\n\r");
+        let data = b"nameserver 1.1.1.1\nsearch example.org\r\n";
+        assert_eq!(from_crlf(&to_crlf(data)), b"nameserver 1.1.1.1\nsearch example.org\n");
+
+        let dir = TempDir::new("crlf");
+        let file = dir.0.join("x.conf");
+        std::fs::write(&file, "a\nb\r\n").unwrap();
+        assert_eq!(crlf_size(&file), Ok(6));
+        assert_eq!(crlf_size(&dir.0.join("missing")), Err(nix::errno::Errno::ENOENT));
+    }
+
+    #[test]
+    fn fresh_writes_bypass_cache() {
+        let (inner, _) = counting_mapper();
+        let mut mapper = CachingMapper::new(inner, Duration::from_secs(3600));
+        mapper.set_fresh_writes(true);
+        assert_eq!(mapper.map(rq(1, false)).unwrap().path, Path::new("/1"));
+        assert_eq!(mapper.map(rq(1, true)).unwrap().path, Path::new("/2"));
+        assert_eq!(mapper.map(rq(1, true)).unwrap().path, Path::new("/3"));
+        assert_eq!(mapper.map(rq(1, false)).unwrap().path, Path::new("/1"));
+    }
+
+    #[test]
+    fn offset_conversions() {
+        assert_eq!(to_off_t(5usize), Ok(5));
+        assert_eq!(to_off_t(u64::MAX), Err(nix::errno::Errno::EOVERFLOW));
+        assert_eq!(buf_index(-1), 0);
+        assert_eq!(buf_index(7), 7);
+    }
+
+    #[test]
+    fn eintr_is_retried() {
+        let mut calls = 0;
+        let result = retry_eintr(|| {
+            calls += 1;
+            if calls < 3 {
+                Err(nix::errno::Errno::EINTR)
+            } else {
+                Ok(calls)
             }
+        });
+        assert_eq!(result, Ok(3));
+        assert_eq!(retry_eintr(|| -> nix::Result<()> { Err(nix::errno::Errno::EIO) }), Err(nix::errno::Errno::EIO));
+
+        let dir = TempDir::new("pwrite");
+        let file = std::fs::File::create(dir.0.join("x.conf")).unwrap();
+        assert_eq!(pwrite_retrying(file.as_raw_fd(), b"abc", 2), Ok(3));
+        assert_eq!(std::fs::read(dir.0.join("x.conf")).unwrap(), b"\0\0abc");
+    }
+
+    #[test]
+    fn target_path_keeps_dots_in_id() {
+        let dir = Path::new("/d");
+        let cases = [
+            ("id", "", "/d/id"),
+            ("id", "conf", "/d/id.conf"),
+            ("id", ".conf", "/d/id.conf"),
+            ("id", "resolv.conf", "/d/id.resolv.conf"),
+            ("my.ns", "conf", "/d/my.ns.conf"),
+        ];
+        for (id, ext, expected) in cases {
+            assert_eq!(build_target_path(dir, id, OsStr::new(ext)), Path::new(expected), "{:?} {:?}", id, ext);
         }
+    }
 
-        let inits_netns = self.procfs.join("1/ns/net");
-        if std::fs::read_link(&inits_netns).is_err() {
-            #[cfg(feature="logging")]
-            eprintln!("Failed to resolve {:?}.\nYou may want to run resolvconffs as root if you want to serve multiple users.", inits_netns);
+    #[test]
+    fn replace_atomically_never_reuses_existing_names() {
+        let dir = TempDir::new("replace");
+        let target = dir.0.join("x.conf");
+        let victim = dir.0.join("victim");
+        std::fs::write(&victim, "keep").unwrap();
+        let planted: Vec<PathBuf> = (0..1000)
+            .map(|n| dir.0.join(format!(".resolvconffs-tmp-x.conf.{}.{}", std::process::id(), n)))
+            .collect();
+        for x in &planted {
+            std::os::unix::fs::symlink(&victim, x).unwrap();
         }
+        let fill = |f: &mut std::fs::File| std::io::Write::write_all(f, b"new").map(|()| 3);
+        assert_eq!(replace_atomically(&target, fill).unwrap_err().kind(), std::io::ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read(&victim).unwrap(), b"keep");
+        for x in &planted {
+            std::fs::remove_file(x).unwrap();
+        }
+        assert_eq!(replace_atomically(&target, fill).unwrap(), 3);
+        assert_eq!(std::fs::read(&target).unwrap(), b"new");
+        assert_eq!(std::fs::read_dir(&dir.0).unwrap().count(), 2, "temporary file left behind");
     }
 
-    fn map(&self, rq: UidGidPid) -> Option<PathBuf> {
-        let mut netnslink = PathBuf::with_capacity(self.backing_directory.as_os_str().len() + 12);
-        netnslink.push(&self.procfs);
-        netnslink.push(format!("{}", rq.pid));
-        netnslink.push("ns/net");
-        let netns = if let Ok(netns) = std::fs::read_link(&netnslink) {
-            netns
-        } else {
-            #[cfg(feature="logging")]
-            eprintln!("Failed to readlink {:?}", netnslink);
-            return None;
-        };
+    #[test]
+    fn only_regular_backing_files_are_opened() {
+        let dir = TempDir::new("regular");
+        let fifo = dir.0.join("fifo.conf");
+        nix::unistd::mkfifo(&fifo, nix::sys::stat::Mode::from_bits_truncate(0o600)).unwrap();
+        let flags = OFlag::O_RDONLY | OFlag::O_CLOEXEC;
+        let mode = nix::sys::stat::Mode::empty();
+        assert_eq!(open_regular(&fifo, flags, mode), Err(nix::errno::Errno::EACCES));
+        assert_eq!(open_regular(&dir.0, flags, mode), Err(nix::errno::Errno::EISDIR));
 
-        let netns = if let Some(x) = netns.to_str() {
-            x
-        } else {
-            #[cfg(feature="logging")]
-            eprintln!("Invalid netns symlink content in {:?}", netnslink);
-            return None;
-        };
-        // net:[4026532413]
+        let file = dir.0.join("x.conf");
+        std::fs::write(&file, "").unwrap();
+        let fd = open_regular(&file, flags, mode).unwrap();
+        let fl = OFlag::from_bits_truncate(nix::fcntl::fcntl(fd, nix::fcntl::FcntlArg::F_GETFL).unwrap());
+        let _ = nix::unistd::close(fd);
+        assert!(!fl.contains(OFlag::O_NONBLOCK));
 
-        let (net, ns) = if let Some(x) = netns.split_once(':') {
-            x
-        } else {
-            #[cfg(feature="logging")]
-            eprintln!("netns symlink content has no `:` character in {:?}", netnslink);
-            return None;
-        };
+        let link = dir.0.join("link.conf");
+        std::os::unix::fs::symlink(&file, &link).unwrap();
+        assert_eq!(stat_backing(&link, false).err(), Some(nix::errno::Errno::ELOOP));
+        assert!(stat_backing(&link, true).is_ok());
+    }
 
-        if net != "net" {
-            #[cfg(feature="logging")]
-            eprintln!("netns symlink content does not start with 'net:' in {:?}", netnslink);
-            return None;
+    #[test]
+    fn pidfiles_older_than_process_start_are_stale() {
+        let dir = TempDir::new("pidfile");
+        let profiles = dir.0.join("profiles");
+        std::fs::create_dir_all(&profiles).unwrap();
+        let arg = format!("pidfile:{}", profiles.display());
+        let mut mapper = netns_fixture(&dir.0, &[100, 200], &["--key-source", &arg]);
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+        let hz = nix::unistd::sysconf(nix::unistd::SysconfVar::CLK_TCK).unwrap().unwrap() as u64;
+        std::fs::write(dir.0.join("proc/stat"), format!("cpu 0 0 0\nbtime {}\n", now - 1000)).unwrap();
+        // Started 500s after boot, and 100s in the future
+        for (pid, since_boot) in [(100, 500), (200, 1100)] {
+            let stat = format!("{} (sleep 1) S{} {} 0 0\n", pid, " 0".repeat(18), since_boot * hz);
+            std::fs::write(dir.0.join(format!("proc/{}/stat", pid)), stat).unwrap();
+            std::fs::write(profiles.join(format!("{}", pid)), "office\n").unwrap();
         }
+        assert_eq!(mapper.map(rq(100, false)).unwrap().path, dir.0.join("backing/office.conf"));
+        let stale = mapper.map(rq(200, false)).unwrap();
+        assert_eq!(stale.path, dir.0.join("default.conf"));
+        assert!(stale.read_only);
+    }
 
-        let nsonly = ns.trim_end_matches(']').trim_start_matches('[');
+    #[test]
+    fn open_flags_need_matching_operations() {
+        let all = OpSet::default();
+        assert!(all.allows_open(libc::O_RDWR | libc::O_TRUNC));
+        let read_only = OpSet::parse("open,read,setattr").unwrap();
+        assert!(read_only.allows_open(libc::O_RDONLY));
+        assert!(!read_only.allows_open(libc::O_WRONLY));
+        assert!(!read_only.allows_open(libc::O_RDWR));
+        let no_setattr = OpSet::parse("open,read,write").unwrap();
+        assert!(no_setattr.allows_open(libc::O_WRONLY));
+        assert!(!no_setattr.allows_open(libc::O_WRONLY | libc::O_TRUNC));
+        assert!(!no_setattr.allows_open(libc::O_RDONLY | libc::O_TRUNC));
+        assert!(!OpSet::parse("read,write,setattr").unwrap().allows_open(libc::O_RDONLY));
+    }
+
+    // The only test running `with_deadline` with a timeout, so filling the pool does not disturb others
+    #[test]
+    fn io_deadlines_fail_fast_when_all_threads_are_stuck() {
+        let dir = TempDir::new("deadline");
+        let mut mapper = netns_fixture(&dir.0, &[100], &["--io-timeout-ms", "5000"]);
+        assert_eq!(mapper.map(rq(100, false)).unwrap().path, dir.0.join("backing/1000.conf"));
+        assert!(dir.0.join("backing/1000.conf").exists());
 
-        let mut targetfile = PathBuf::with_capacity(self.backing_directory.as_os_str().len() + 2 + nsonly.len() + self.extension.as_os_str().len());
-        targetfile.push(&self.backing_directory);
-        targetfile.push(nsonly);
-        if self.extension.as_os_str().len() > 0 {
-            targetfile.set_extension(self.extension.as_os_str());
+        let (release, stuck) = std::sync::mpsc::channel::<()>();
+        let stuck = Arc::new(std::sync::Mutex::new(stuck));
+        for _ in 0..IO_POOL_MAX_THREADS {
+            let stuck = stuck.clone();
+            let wait = move || stuck.lock().unwrap().recv().map_err(|_| nix::errno::Errno::EPIPE);
+            assert_eq!(with_deadline(Some(Duration::from_millis(1)), wait, drop), Err(nix::errno::Errno::ETIMEDOUT));
         }
+        let started = Instant::now();
+        assert_eq!(with_deadline(Some(Duration::from_secs(5)), || Ok(()), drop), Err(nix::errno::Errno::EIO));
+        assert!(started.elapsed() < Duration::from_secs(1));
 
-        if let Some(ref deffile) = self.default_file {
-            if std::fs::metadata(&targetfile).is_err() {
-                if std::fs::copy(deffile, &targetfile).is_err() {
-                    #[cfg(feature="logging")]
-                    eprintln!("Cannot copy from {:?} to {:?}", deffile, targetfile);
-                }
-            }
-        } 
+        for _ in 0..IO_POOL_MAX_THREADS {
+            release.send(()).unwrap();
+        }
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while with_deadline(Some(Duration::from_secs(5)), || Ok(()), drop).is_err() {
+            assert!(Instant::now() < deadline, "pool threads did not become idle");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
 
-        Some(targetfile)
+    #[test]
+    #[ignore = "needs /dev/fuse and permission to mount"]
+    fn direct_io_reads_see_backing_file_changes() {
+        use std::os::unix::fs::FileExt;
+        let dir = TempDir::new("directio");
+        let backing = dir.0.join("backing.conf");
+        let mountpoint = dir.0.join("resolv.conf");
+        std::fs::write(&backing, "nameserver 10.0.0.1\n").unwrap();
+        std::fs::write(&mountpoint, "").unwrap();
+        let path = backing.clone();
+        let fs = FileMapperFs::new(move |_| Some(Mapping::from(path.clone())));
+        let session = spawn_mount2(fs, &mountpoint, &[fuser::MountOption::FSName("resolvconffs".to_owned())]).unwrap();
+        let file = std::fs::File::open(&mountpoint).unwrap();
+        let mut buf = [0; 64];
+        let n = file.read_at(&mut buf, 0).unwrap();
+        assert_eq!(&buf[..n], b"nameserver 10.0.0.1\n");
+        // Same length, so that a cached size could not hide a stale read either
+        std::fs::write(&backing, "nameserver 10.0.0.2\n").unwrap();
+        let n = file.read_at(&mut buf, 0).unwrap();
+        assert_eq!(&buf[..n], b"nameserver 10.0.0.2\n");
+        drop(file);
+        drop(session);
     }
-}
 
-fn main() -> std::io::Result<()> {
-    use fuser::MountOption;
+    #[test]
+    fn prewarm_only_stats_backing_files() {
+        let dir = TempDir::new("prewarm");
+        let hook = dir.0.join("hook");
+        std::fs::write(&hook, "#!/bin/sh\ntouch \"$0.ran\"\n").unwrap();
+        std::fs::set_permissions(&hook, std::os::unix::fs::PermissionsExt::from_mode(0o755)).unwrap();
+        let audit_log = dir.0.join("audit.log");
+        let mut mapper = netns_fixture(
+            &dir.0,
+            &[100, 101, 200],
+            &["--on-new-netns", hook.to_str().unwrap(), "--audit-log", audit_log.to_str().unwrap()],
+        );
+        // 100 and 101 share netns 1000
+        std::fs::remove_file(dir.0.join("proc/101/ns/net")).unwrap();
+        std::os::unix::fs::symlink("net:[1000]", dir.0.join("proc/101/ns/net")).unwrap();
+        std::fs::write(dir.0.join("backing/1000.conf"), "nameserver 10.0.0.1\n").unwrap();
+        assert_eq!(mapper.prewarm(), 1);
+        // No default copy for 2000, no hook run, no audit records
+        assert!(!dir.0.join("backing/2000.conf").exists());
+        std::thread::sleep(Duration::from_millis(200));
+        assert!(!dir.0.join("hook.ran").exists());
+        assert_eq!(std::fs::read_to_string(&audit_log).unwrap_or_default(), "");
+    }
 
-    #[cfg(feature="logging")]
-    env_logger::init();
+    #[test]
+    fn truncate_then_getattr_sees_new_size() {
+        let dir = TempDir::new("truncate");
+        let bf = dir.0.join("backing.conf");
+        std::fs::write(&bf, "nameserver 10.0.0.1\n").unwrap();
+        let mut fs = FileMapperFs::new(|_| None);
+        let fd = nix::fcntl::open(&bf, OFlag::O_RDWR | OFlag::O_CLOEXEC, nix::sys::stat::Mode::empty()).unwrap();
+        fs.register_handle(fd as u64, &bf, 0);
+        fs.truncate_backing(&bf, 4, Some(fd as u64)).unwrap();
+        assert_eq!(fs.attr_after_setattr(&bf, Some(fd as u64)).unwrap().st_size, 4);
+        assert_eq!(fs.attr_after_setattr(&bf, None).unwrap().st_size, 4);
+        // The descriptor keeps describing the truncated file after the path is replaced
+        std::fs::write(dir.0.join("new.conf"), "nameserver 10.0.0.2\n").unwrap();
+        std::fs::rename(dir.0.join("new.conf"), &bf).unwrap();
+        fs.truncate_backing(&bf, 2, Some(fd as u64)).unwrap();
+        assert_eq!(fs.attr_after_setattr(&bf, Some(fd as u64)).unwrap().st_size, 2);
+        nix::unistd::close(fd).unwrap();
+        fs.truncate_backing(&bf, 10, None).unwrap();
+        assert_eq!(fs.attr_after_setattr(&bf, None).unwrap().st_size, 10);
+        assert_eq!(std::fs::read(&bf).unwrap(), b"nameserver");
 
-    let opts: Opts = gumdrop::parse_args_or_exit(gumdrop::ParsingStyle::AllOptions);
+        let mut fs = FileMapperFs::with_options(|_| None, FsOptions { crlf: true, ..FsOptions::default() });
+        std::fs::write(&bf, "a\nb\n").unwrap();
+        fs.truncate_backing(&bf, 3, None).unwrap();
+        assert_eq!(fs.attr_after_setattr(&bf, None).unwrap().st_size, 3);
+        assert_eq!(std::fs::read(&bf).unwrap(), b"a\n");
+    }
 
-    let mapper = NetnsMapper {
-        backing_directory: opts.backing_directory,
-        extension: opts.extension,
-        default_file: opts.default_file,
-        procfs: opts.procfs,
-    };
+    #[test]
+    fn read_only_mappings_refuse_writes_with_erofs() {
+        let mapping = Mapping::from(PathBuf::from("/backing/x.conf"));
+        let mut fs = FileMapperFs::new(|_| None);
+        assert_eq!(fs.check_writable(&mapping, None), Ok(()));
+        let read_only = Mapping { read_only: true, ..mapping.clone() };
+        assert_eq!(fs.check_writable(&read_only, None), Err(nix::errno::Errno::EROFS));
+        let content = Mapping { content: Some(Arc::from(&b"nameserver 10.0.0.1\n"[..])), ..mapping.clone() };
+        assert_eq!(fs.check_writable(&content, None), Err(nix::errno::Errno::EROFS));
+        let fh = fs.register_memory_handle(Arc::from(&b""[..]), &mapping.path, 0).unwrap();
+        assert_eq!(fs.check_writable(&mapping, Some(fh)), Err(nix::errno::Errno::EROFS));
 
-    mapper.sanity_check();
+        let fs = FileMapperFs::with_options(|_| None, FsOptions { read_prefix: Some(Arc::from(&b"# x\n"[..])), ..FsOptions::default() });
+        assert_eq!(fs.check_writable(&mapping, None), Err(nix::errno::Errno::EROFS));
+    }
 
-    let mut fuse_opts = Vec::<MountOption>::with_capacity(3 + opts.other_fuse_opts.len());
-    fuse_opts.push(MountOption::FSName("resolvconffs".to_owned()));
-    fuse_opts.push(MountOption::DefaultPermissions);
-    fuse_opts.push(MountOption::AllowOther);
-    let fs = FileMapperFs::new(move |rq| mapper.map(rq));
+    #[test]
+    #[ignore = "needs root to mount"]
+    fn writes_on_read_only_backing_fs_fail_with_erofs() {
+        use nix::mount::{mount, umount2, MntFlags, MsFlags};
+        let dir = TempDir::new("rofs");
+        let file = dir.0.join("x.conf");
+        std::fs::write(&file, "nameserver 10.0.0.1\n").unwrap();
+        mount(Some(&dir.0), &dir.0, None::<&str>, MsFlags::MS_BIND, None::<&str>).unwrap();
+        let remounted = mount(None::<&str>, &dir.0, None::<&str>, MsFlags::MS_BIND | MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY, None::<&str>);
+        let denied = write_denied_errno(&file);
+        let mut fs = FileMapperFs::new(|_| None);
+        let truncated = fs.truncate_backing(&file, 0, None);
+        let opened = open_regular(&file, OFlag::O_WRONLY | OFlag::O_CLOEXEC, nix::sys::stat::Mode::empty());
+        umount2(&dir.0, MntFlags::MNT_DETACH).unwrap();
+        remounted.unwrap();
+        assert_eq!(denied, libc::EROFS);
+        assert_eq!(truncated, Err(nix::errno::Errno::EROFS));
+        assert_eq!(opened, Err(nix::errno::Errno::EROFS));
+    }
+
+    #[test]
+    fn writes_interrupted_by_signals_are_retried() {
+        use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+        use std::sync::atomic::{AtomicU32, Ordering};
+        extern "C" fn ignore(_: libc::c_int) {}
+        // No SA_RESTART, so that the blocked write fails with EINTR
+        let action = SigAction::new(SigHandler::Handler(ignore), SaFlags::empty(), SigSet::empty());
+        let old = unsafe { sigaction(Signal::SIGUSR2, &action) }.unwrap();
+        let (r, w) = nix::unistd::pipe2(OFlag::O_CLOEXEC | OFlag::O_NONBLOCK).unwrap();
+        while nix::unistd::write(w, &[0; 4096]).is_ok() {}
+        nix::fcntl::fcntl(w, nix::fcntl::FcntlArg::F_SETFL(OFlag::empty())).unwrap();
+        // Pipes cannot be written with `pwrite`, so this calls `retry_eintr` like `pwrite_retrying` does
+        let attempts = std::sync::Arc::new(AtomicU32::new(0));
+        let (tx, rx) = std::sync::mpsc::channel();
+        let writer = {
+            let attempts = attempts.clone();
+            std::thread::spawn(move || {
+                tx.send(nix::sys::pthread::pthread_self()).unwrap();
+                retry_eintr(|| {
+                    attempts.fetch_add(1, Ordering::SeqCst);
+                    nix::unistd::write(w, b"x")
+                })
+            })
+        };
+        let thread = rx.recv().unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while attempts.load(Ordering::SeqCst) < 2 && Instant::now() < deadline {
+            nix::sys::pthread::pthread_kill(thread, Signal::SIGUSR2).unwrap();
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let interrupted = attempts.load(Ordering::SeqCst) >= 2;
+        let mut buf = [0; 65536];
+        nix::unistd::read(r, &mut buf).unwrap();
+        assert_eq!(writer.join().unwrap(), Ok(1));
+        unsafe { sigaction(Signal::SIGUSR2, &old) }.unwrap();
+        nix::unistd::close(r).unwrap();
+        nix::unistd::close(w).unwrap();
+        assert!(interrupted, "write was never interrupted");
+    }
+
+    #[test]
+    fn permission_checks_consider_supplementary_groups() {
+        let mut st: nix::sys::stat::FileStat = unsafe { std::mem::zeroed() };
+        st.st_mode = libc::S_IFREG | 0o640;
+        st.st_uid = 1000;
+        st.st_gid = 100;
+        let opts = FsOptions::default();
+        let unused = || -> Vec<u32> { panic!("groups looked up needlessly") };
+        assert!(permits(&st, &opts, 1000, 1000, unused, libc::R_OK | libc::W_OK));
+        assert!(permits(&st, &opts, 1001, 100, unused, libc::R_OK));
+        assert!(permits(&st, &opts, 0, 0, unused, libc::R_OK | libc::W_OK));
+        assert!(permits(&st, &opts, 1001, 1001, || vec![50, 100], libc::R_OK));
+        assert!(!permits(&st, &opts, 1001, 1001, || vec![100], libc::W_OK));
+        assert!(!permits(&st, &opts, 1001, 1001, || vec![50], libc::R_OK));
 
-    for x in opts.other_fuse_opts {
-        fuse_opts.push(MountOption::CUSTOM(x));
+        let dir = TempDir::new("groups");
+        std::fs::create_dir_all(dir.0.join("100")).unwrap();
+        std::fs::write(dir.0.join("100/status"), "Name:\tx\nGroups:\t4 24 100 \nNSpid:\t100\n").unwrap();
+        assert_eq!(read_groups(&PidDir::open(&dir.0, 100).unwrap()), Some(vec![4, 24, 100]));
+        std::fs::write(dir.0.join("100/status"), "Name:\tx\nGroups:\n").unwrap();
+        assert_eq!(read_groups(&PidDir::open(&dir.0, 100).unwrap()), Some(vec![]));
     }
 
-    if std::fs::symlink_metadata(&opts.mountpoint_file)
-        .map(|x| x.is_file())
-        .ok()
-        != Some(true)
-    {
-        #[cfg(feature="logging")]
-        eprintln!("Use regular file as a mountpoint, not a directory.");
+    #[test]
+    fn refresh_defaults_rewrites_only_unmodified_copies() {
+        let dir = TempDir::new("refresh");
+        let mut mapper = netns_fixture(&dir.0, &[100, 200, 300], &[]);
+        for pid in [100, 200, 300] {
+            assert_eq!(mapper.map(rq(pid, false)).unwrap().source, Source::Default);
+        }
+        let (touched, edited, untouched) = (dir.0.join("backing/1000.conf"), dir.0.join("backing/2000.conf"), dir.0.join("backing/3000.conf"));
+        // Rewritten with the same content, as editors do on save
+        std::fs::remove_file(&touched).unwrap();
+        std::fs::write(&touched, "nameserver 127.0.0.1\n").unwrap();
+        std::fs::write(&edited, "nameserver 10.0.0.1\n").unwrap();
+        std::fs::write(dir.0.join("default.conf"), "nameserver 127.0.0.53\n").unwrap();
+        mapper.refresh_defaults();
+        assert_eq!(std::fs::read_to_string(&touched).unwrap(), "nameserver 127.0.0.53\n");
+        assert_eq!(std::fs::read_to_string(&edited).unwrap(), "nameserver 10.0.0.1\n");
+        assert_eq!(std::fs::read_to_string(&untouched).unwrap(), "nameserver 127.0.0.53\n");
+        assert_eq!(mapper.map(rq(100, false)).unwrap().source, Source::Default);
+        assert_eq!(mapper.map(rq(200, false)).unwrap().source, Source::Own);
+        assert_eq!(mapper.populated.len(), 2);
     }
 
-    fuser::mount2(fs, opts.mountpoint_file, &fuse_opts)
+    #[test]
+    fn audit_log_records_new_and_changed_mappings_only() {
+        let dir = TempDir::new("audit");
+        let path = dir.0.join("audit.log");
+        let mut log = AuditLog::open(&path).unwrap();
+        let (a, b) = (Path::new("/backing/1.conf"), Path::new("/backing/2.conf"));
+        log.record(rq(100, false), "1", a).unwrap();
+        log.record(rq(101, false), "1", a).unwrap();
+        log.record(rq(100, false), "2", b).unwrap();
+        log.record(rq(100, false), "1", b).unwrap();
+        log.record(rq(100, false), "1", b).unwrap();
+        let other_uid = UidGidPid { uid: 0, ..rq(100, false) };
+        log.record(other_uid, "1", b).unwrap();
+        let lines = |path: &Path| -> Vec<String> {
+            let content = std::fs::read_to_string(path).unwrap();
+            content.lines().map(|x| x.split_once(' ').unwrap().1.to_owned()).collect()
+        };
+        assert_eq!(
+            lines(&path),
+            [
+                r#"uid=1000 gid=1000 pid=100 id="1" path="/backing/1.conf""#,
+                r#"uid=1000 gid=1000 pid=100 id="2" path="/backing/2.conf""#,
+                r#"uid=1000 gid=1000 pid=100 id="1" path="/backing/2.conf""#,
+                r#"uid=0 gid=1000 pid=100 id="1" path="/backing/2.conf""#,
+            ]
+        );
+        // Rotated file starts over
+        std::fs::rename(&path, dir.0.join("audit.log.1")).unwrap();
+        log.reopen().unwrap();
+        log.record(rq(100, false), "1", b).unwrap();
+        assert_eq!(lines(&path), [r#"uid=1000 gid=1000 pid=100 id="1" path="/backing/2.conf""#]);
+    }
 }